@@ -76,7 +76,7 @@ fn bench_resize_buffer_pool_vec(b: &mut Bencher) {
 #[bench]
 fn bench_resize_vec_of_vecs(b: &mut Bencher) {
     b.iter(|| {
-        let mut data = vec![vec![0 as usize; MIN]; MIN];
+        let mut data = vec![vec![0_usize; MIN]; MIN];
 
         let mut temp: Vec<Vec<usize>> = Vec::with_capacity(MAX);
 
@@ -101,7 +101,7 @@ fn bench_resize_vec_of_vecs(b: &mut Bencher) {
         assert_eq!(value, (MIN * 2) - 6);
 
         data.iter_mut().for_each(|array| array.resize(MAX, 0));
-        data.resize(MAX, vec![0 as usize; MAX]);
+        data.resize(MAX, vec![0_usize; MAX]);
 
         for _ in 0..MAX {
             temp.push(data.pop().unwrap());
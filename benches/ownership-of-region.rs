@@ -31,7 +31,7 @@ fn bench_ownership_buffer_pool_vec(b: &mut Bencher) {
             .map(|x| x.as_ref().iter().fold(0, |a, b| (a + b) / 2))
             .fold(0, |a, b| (a + b) / 2);
 
-        temp.drain(..).for_each(|x| drop(x));
+        temp.drain(..).for_each(drop);
 
         assert_eq!(value, MAX * 2 - 6);
     });
@@ -39,7 +39,7 @@ fn bench_ownership_buffer_pool_vec(b: &mut Bencher) {
 
 #[bench]
 fn bench_ownership_vec_of_vecs(b: &mut Bencher) {
-    let mut data = vec![vec![0 as usize; MAX]; MAX];
+    let mut data = vec![vec![0_usize; MAX]; MAX];
     let mut temp = Vec::with_capacity(MAX);
 
     b.iter(|| {
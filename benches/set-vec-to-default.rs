@@ -26,7 +26,7 @@ fn bench_default_buffer_pool_vec(b: &mut Bencher) {
 
 #[bench]
 fn bench_default_vec_of_vecs(b: &mut Bencher) {
-    let mut data = vec![vec![0 as usize; MAX]; MAX];
+    let mut data = vec![vec![0_usize; MAX]; MAX];
 
     b.iter(|| {
         for value in data.iter_mut() {
@@ -41,7 +41,7 @@ fn bench_iter_vec_of_vecs(b: &mut Bencher) {
     let mut data = vec![];
 
     for _ in 0..COUNT {
-        data.push(vec![0 as usize; BUFFER_SIZE]);
+        data.push(vec![0_usize; BUFFER_SIZE]);
     }
 
     b.iter(|| {
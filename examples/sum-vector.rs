@@ -6,9 +6,9 @@ fn main() {
         .with_capacity(100)
         .build();
     let mut buffer = pool.get_cleared_space().unwrap();
-    for (index, value) in buffer.as_mut().iter_mut().enumerate() {
+    for (index, value) in buffer.iter_mut().enumerate() {
         *value = index;
     }
-    let sum: usize = buffer.as_ref().iter().sum();
+    let sum: usize = buffer.iter().sum();
     println!("Sum {}", sum);
 }
@@ -0,0 +1,193 @@
+use alloc::alloc::{alloc, dealloc, handle_alloc_error, realloc, Layout};
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+
+/// The size of a cache line, or of the pair of lines that are prefetched
+/// together, on the target.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+pub(crate) const CACHE_LINE: usize = 128;
+#[cfg(target_arch = "s390x")]
+pub(crate) const CACHE_LINE: usize = 256;
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64",
+    target_arch = "s390x"
+)))]
+pub(crate) const CACHE_LINE: usize = 64;
+
+/// The number of values from the start of one buffer to the start of the
+/// next, so that every buffer starts on a multiple of `align` bytes.
+pub(crate) fn stride<V>(buffer_size: usize, align: usize) -> usize {
+    let size = size_of::<V>();
+
+    if size == 0 {
+        return buffer_size;
+    }
+
+    let unit = align / gcd(align, size);
+    buffer_size.div_ceil(unit) * unit
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// A heap allocation of values that starts on a multiple of `align` bytes.
+pub(crate) struct AlignedBuffer<V> {
+    ptr: NonNull<V>,
+    len: usize,
+    align: usize,
+}
+
+unsafe impl<V: Send> Send for AlignedBuffer<V> {}
+unsafe impl<V: Sync> Sync for AlignedBuffer<V> {}
+
+impl<V> AlignedBuffer<V> {
+    /// An empty buffer, aligned to at least `align_of::<V>()`.
+    pub fn new(align: usize) -> AlignedBuffer<V> {
+        let align = align.max(align_of::<V>());
+
+        AlignedBuffer {
+            ptr: dangling(align),
+            len: 0,
+            align,
+        }
+    }
+
+    fn layout(&self, len: usize) -> Layout {
+        size_of::<V>()
+            .checked_mul(len)
+            .and_then(|size| Layout::from_size_align(size, self.align).ok())
+            .expect("Capacity overflow")
+    }
+
+    /// Change the number of values, creating new values with `f` and dropping
+    /// the values past the new length. The values may move.
+    pub fn resize_with(&mut self, new_len: usize, mut f: impl FnMut() -> V) {
+        let old_len = self.len;
+
+        if new_len < old_len {
+            self.len = new_len;
+
+            unsafe {
+                ptr::drop_in_place(ptr::slice_from_raw_parts_mut(
+                    self.ptr.as_ptr().add(new_len),
+                    old_len - new_len,
+                ));
+            }
+        }
+
+        self.reallocate(old_len, new_len);
+
+        while self.len < new_len {
+            unsafe {
+                self.ptr.as_ptr().add(self.len).write(f());
+            }
+
+            self.len += 1;
+        }
+    }
+
+    /// Move the allocation of `old_len` values to one that fits `new_len`.
+    fn reallocate(&mut self, old_len: usize, new_len: usize) {
+        let old_layout = self.layout(old_len);
+        let new_layout = self.layout(new_len);
+
+        if old_layout.size() == new_layout.size() {
+            return;
+        }
+
+        if new_layout.size() == 0 {
+            unsafe { dealloc(self.ptr.as_ptr().cast(), old_layout) };
+            self.ptr = dangling(self.align);
+            return;
+        }
+
+        let ptr = unsafe {
+            if old_layout.size() == 0 {
+                alloc(new_layout)
+            } else {
+                realloc(self.ptr.as_ptr().cast(), old_layout, new_layout.size())
+            }
+        };
+
+        self.ptr = NonNull::new(ptr.cast()).unwrap_or_else(|| handle_alloc_error(new_layout));
+    }
+}
+
+fn dangling<V>(align: usize) -> NonNull<V> {
+    NonNull::new(ptr::null_mut::<u8>().wrapping_add(align).cast()).expect("Alignment is never zero")
+}
+
+impl<V> Deref for AlignedBuffer<V> {
+    type Target = [V];
+
+    fn deref(&self) -> &[V] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<V> DerefMut for AlignedBuffer<V> {
+    fn deref_mut(&mut self) -> &mut [V] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<V> Drop for AlignedBuffer<V> {
+    fn drop(&mut self) {
+        self.resize_with(0, || unreachable!());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_pad_the_stride_to_the_alignment() {
+        assert_eq!(stride::<f32>(10, 4), 10);
+        assert_eq!(stride::<f32>(10, 32), 16);
+        assert_eq!(stride::<f32>(16, 64), 16);
+        assert_eq!(stride::<[u8; 3]>(10, 32), 32);
+        assert_eq!(stride::<[u8; 12]>(1, 32), 8);
+        assert_eq!(stride::<()>(10, 64), 10);
+    }
+
+    #[test]
+    fn it_should_keep_values_aligned_when_resized() {
+        let mut buffer: AlignedBuffer<u8> = AlignedBuffer::new(64);
+
+        for len in [1, 100, 3, 0, 5000, 64] {
+            buffer.resize_with(len, || 7);
+
+            assert_eq!(buffer.len(), len);
+            assert_eq!(buffer.as_ptr() as usize % 64, 0);
+            assert!(buffer.iter().all(|value| *value == 7));
+        }
+    }
+
+    #[test]
+    fn it_should_drop_values() {
+        let value = alloc::rc::Rc::new(());
+        let mut buffer = AlignedBuffer::new(32);
+
+        buffer.resize_with(10, || alloc::rc::Rc::clone(&value));
+        assert_eq!(alloc::rc::Rc::strong_count(&value), 11);
+
+        buffer.resize_with(4, || unreachable!());
+        assert_eq!(alloc::rc::Rc::strong_count(&value), 5);
+
+        drop(buffer);
+        assert_eq!(alloc::rc::Rc::strong_count(&value), 1);
+    }
+}
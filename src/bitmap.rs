@@ -0,0 +1,367 @@
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::aligned::{AlignedBuffer, CACHE_LINE};
+use crate::BufferPoolError;
+
+const BITS_IN_U64: usize = 64;
+
+/// The occupancy bitmap shared by a pool and its references, one bit per
+/// buffer.
+///
+/// Claiming and freeing bits never blocks or allocates. A claim visits each
+/// word once, finds a free bit with `trailing_zeros` and takes it with a
+/// compare-and-swap, retrying a word at most once per bit before moving on,
+/// so the worst case is bounded by the size of the bitmap.
+///
+/// A cache padded bitmap keeps its values on cache lines of their own, so
+/// claiming a buffer never contends with writes to anything else.
+pub(crate) struct Bitmap {
+    values: AlignedBuffer<AtomicU64>,
+    len: usize,
+    cache_padded: bool,
+}
+
+impl Bitmap {
+    pub fn new(len: usize) -> Bitmap {
+        Bitmap::with_padding(len, false)
+    }
+
+    pub fn cache_padded(len: usize) -> Bitmap {
+        Bitmap::with_padding(len, true)
+    }
+
+    fn with_padding(len: usize, cache_padded: bool) -> Bitmap {
+        let mut bitmap = Bitmap {
+            values: AlignedBuffer::new(if cache_padded { CACHE_LINE } else { 0 }),
+            len: 0,
+            cache_padded,
+        };
+        bitmap.resize(len);
+        bitmap
+    }
+
+    /// The number of bits in the bitmap.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Grow or shrink the bitmap, keeping the state of the bits that remain.
+    pub fn resize(&mut self, len: usize) {
+        let used_values = len.div_ceil(BITS_IN_U64);
+        let values = if self.cache_padded {
+            let values_per_line = CACHE_LINE / size_of::<AtomicU64>();
+            used_values.div_ceil(values_per_line) * values_per_line
+        } else {
+            used_values
+        };
+
+        self.values.resize_with(values, || AtomicU64::new(0));
+        self.len = len;
+
+        for value in self.values[used_values..].iter_mut() {
+            *value.get_mut() = 0;
+        }
+
+        if let Some(value_index) = used_values.checked_sub(1) {
+            let mask = self.mask(value_index);
+            *self.values[value_index].get_mut() &= mask;
+        }
+    }
+
+    /// The values that hold the bits, without any padding.
+    fn used_values(&self) -> &[AtomicU64] {
+        &self.values[..self.len.div_ceil(BITS_IN_U64)]
+    }
+
+    /// The bits of the value at `value_index` that are inside the bitmap.
+    fn mask(&self, value_index: usize) -> u64 {
+        let remaining = self.len - value_index * BITS_IN_U64;
+
+        if remaining >= BITS_IN_U64 {
+            u64::MAX
+        } else {
+            (1 << remaining) - 1
+        }
+    }
+
+    /// Find a free bit and mark it as used, returning its index.
+    pub fn find_free_index_and_use(&self) -> Option<usize> {
+        for (value_index, value) in self.used_values().iter().enumerate() {
+            let mask = self.mask(value_index);
+            let mut current = value.load(Ordering::Relaxed);
+
+            for _ in 0..BITS_IN_U64 {
+                let free = !current & mask;
+
+                if free == 0 {
+                    break;
+                }
+
+                let offset = free.trailing_zeros() as usize;
+                let bit = 1 << offset;
+
+                match value.compare_exchange(
+                    current,
+                    current | bit,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Some(value_index * BITS_IN_U64 + offset),
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Find `len` adjacent free bits that don't cross a multiple of
+    /// `boundary` and mark them as used, returning the index of the first.
+    pub fn find_free_range_and_use(&self, len: usize, boundary: usize) -> Option<usize> {
+        let mut start = 0;
+
+        while len > 0 && len <= boundary && start + len <= self.len {
+            if start / boundary != (start + len - 1) / boundary {
+                start = (start / boundary + 1) * boundary;
+            } else if let Some(used) = self.find_used_index(start, len) {
+                start = used + 1;
+            } else if self.use_range(start, len) {
+                return Some(start);
+            }
+        }
+
+        None
+    }
+
+    fn find_used_index(&self, start: usize, len: usize) -> Option<usize> {
+        range_masks(start, len).find_map(|(value_index, mask)| {
+            let used = self.values[value_index].load(Ordering::Relaxed) & mask;
+
+            if used == 0 {
+                None
+            } else {
+                Some(value_index * BITS_IN_U64 + used.trailing_zeros() as usize)
+            }
+        })
+    }
+
+    /// Try to mark a range of free bits as used, undoing any partial progress
+    /// if one of them was taken in the meantime.
+    fn use_range(&self, start: usize, len: usize) -> bool {
+        for (value_index, mask) in range_masks(start, len) {
+            let value = &self.values[value_index];
+            let mut current = value.load(Ordering::Relaxed);
+
+            loop {
+                if current & mask != 0 {
+                    let used = (value_index * BITS_IN_U64).saturating_sub(start);
+                    self.set_range_free_unchecked(start, used);
+                    return false;
+                }
+
+                match value.compare_exchange_weak(
+                    current,
+                    current | mask,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Mark `len` bits starting at `index` as free.
+    pub fn set_range_free(&self, index: usize, len: usize) -> Result<(), BufferPoolError> {
+        if index + len <= self.len {
+            self.set_range_free_unchecked(index, len);
+            Ok(())
+        } else {
+            Err(BufferPoolError::IndexOutOfRange {
+                index: index + len - 1,
+                capacity: self.len,
+            })
+        }
+    }
+
+    fn set_range_free_unchecked(&self, index: usize, len: usize) {
+        for (value_index, mask) in range_masks(index, len) {
+            self.values[value_index].fetch_and(!mask, Ordering::Release);
+        }
+    }
+
+    /// The number of bits that are used.
+    pub fn count_used(&self) -> usize {
+        self.used_values()
+            .iter()
+            .map(|value| value.load(Ordering::Acquire).count_ones() as usize)
+            .sum()
+    }
+
+    /// Whether any of the bits are used.
+    pub fn any_used(&self) -> bool {
+        self.used_values()
+            .iter()
+            .any(|value| value.load(Ordering::Acquire) != 0)
+    }
+
+    /// The index of the last bit that is used.
+    pub fn last_used(&self) -> Option<usize> {
+        self.used_values()
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(value_index, value)| match value.load(Ordering::Acquire) {
+                0 => None,
+                value => Some(
+                    value_index * BITS_IN_U64 + BITS_IN_U64 - 1 - value.leading_zeros() as usize,
+                ),
+            })
+    }
+}
+
+/// The values that a range of bits touches, along with the mask of the bits
+/// inside each value.
+fn range_masks(start: usize, len: usize) -> impl Iterator<Item = (usize, u64)> {
+    let end = start + len;
+
+    (start / BITS_IN_U64..end.div_ceil(BITS_IN_U64)).map(move |value_index| {
+        let offset = value_index * BITS_IN_U64;
+        let low = start.max(offset) - offset;
+        let high = end.min(offset + BITS_IN_U64) - offset;
+
+        let mask = if high - low == BITS_IN_U64 {
+            u64::MAX
+        } else {
+            ((1 << (high - low)) - 1) << low
+        };
+
+        (value_index, mask)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_use_the_lowest_free_index() {
+        let bitmap = Bitmap::new(130);
+
+        for index in 0..130 {
+            assert_eq!(bitmap.find_free_index_and_use(), Some(index));
+        }
+
+        assert_eq!(bitmap.find_free_index_and_use(), None);
+
+        bitmap.set_range_free(70, 1).unwrap();
+        bitmap.set_range_free(3, 1).unwrap();
+
+        assert_eq!(bitmap.find_free_index_and_use(), Some(3));
+        assert_eq!(bitmap.find_free_index_and_use(), Some(70));
+    }
+
+    #[test]
+    fn it_should_not_use_indices_past_the_end() {
+        let bitmap = Bitmap::new(3);
+
+        for _ in 0..3 {
+            assert!(bitmap.find_free_index_and_use().is_some());
+        }
+
+        assert_eq!(bitmap.find_free_index_and_use(), None);
+        assert_eq!(bitmap.count_used(), 3);
+        assert_eq!(
+            bitmap.set_range_free(3, 1),
+            Err(BufferPoolError::IndexOutOfRange {
+                index: 3,
+                capacity: 3
+            })
+        );
+    }
+
+    #[test]
+    fn it_should_keep_used_bits_when_resized() {
+        let mut bitmap = Bitmap::new(0);
+
+        assert_eq!(bitmap.find_free_index_and_use(), None);
+        assert!(!bitmap.any_used());
+
+        bitmap.resize(64);
+        assert_eq!(bitmap.find_free_index_and_use(), Some(0));
+
+        bitmap.resize(65);
+        assert_eq!(bitmap.len(), 65);
+        assert!(bitmap.any_used());
+        assert_eq!(bitmap.find_free_index_and_use(), Some(1));
+
+        bitmap.resize(0);
+        assert!(!bitmap.any_used());
+    }
+
+    #[test]
+    fn it_should_use_ranges_of_free_bits() {
+        let bitmap = Bitmap::new(200);
+
+        assert_eq!(bitmap.find_free_index_and_use(), Some(0));
+        assert_eq!(bitmap.find_free_range_and_use(100, usize::MAX), Some(1));
+        assert_eq!(bitmap.count_used(), 101);
+        assert_eq!(bitmap.find_free_index_and_use(), Some(101));
+
+        assert_eq!(bitmap.find_free_range_and_use(99, usize::MAX), None);
+        assert_eq!(bitmap.find_free_range_and_use(98, usize::MAX), Some(102));
+
+        bitmap.set_range_free(1, 100).unwrap();
+
+        assert_eq!(bitmap.count_used(), 100);
+        assert_eq!(bitmap.find_free_range_and_use(0, usize::MAX), None);
+        assert_eq!(bitmap.find_free_range_and_use(100, usize::MAX), Some(1));
+    }
+
+    #[test]
+    fn it_should_find_the_last_used_bit() {
+        let bitmap = Bitmap::new(130);
+
+        assert_eq!(bitmap.last_used(), None);
+
+        bitmap.find_free_range_and_use(70, usize::MAX).unwrap();
+        assert_eq!(bitmap.last_used(), Some(69));
+
+        bitmap.set_range_free(1, 69).unwrap();
+        assert_eq!(bitmap.last_used(), Some(0));
+    }
+
+    #[test]
+    fn it_should_not_use_ranges_across_a_boundary() {
+        let bitmap = Bitmap::new(12);
+
+        assert_eq!(bitmap.find_free_index_and_use(), Some(0));
+        assert_eq!(bitmap.find_free_range_and_use(4, 4), Some(4));
+        assert_eq!(bitmap.find_free_range_and_use(3, 4), Some(1));
+        assert_eq!(bitmap.find_free_range_and_use(2, 4), Some(8));
+        assert_eq!(bitmap.find_free_range_and_use(5, 4), None);
+    }
+
+    #[test]
+    fn it_should_keep_cache_padded_values_on_their_own_lines() {
+        let mut bitmap = Bitmap::cache_padded(65);
+        let bytes = bitmap.values.len() * size_of::<AtomicU64>();
+
+        assert_eq!(bitmap.values.as_ptr() as usize % CACHE_LINE, 0);
+        assert_eq!(bytes % CACHE_LINE, 0);
+
+        assert!(bitmap.find_free_range_and_use(65, usize::MAX).is_some());
+        assert_eq!(bitmap.find_free_index_and_use(), None);
+
+        bitmap.resize(1);
+        assert_eq!(bitmap.count_used(), 1);
+
+        bitmap.resize(200);
+        assert_eq!(bitmap.count_used(), 1);
+        assert_eq!(bitmap.find_free_index_and_use(), Some(1));
+    }
+}
@@ -0,0 +1,414 @@
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use crate::aligned::{stride, AlignedBuffer};
+use crate::storage::Initializer;
+use crate::{BufferPoolBuilder, BufferPoolError};
+
+const BITS_IN_U64: usize = 64;
+
+/// The free blocks of a buddy allocator, measured in units of `buffer_size`.
+///
+/// Each order has a bitset of the blocks of `1 << order` units that are free.
+/// When the capacity isn't a power of two, the region starts out as the
+/// largest blocks that fit, and blocks only coalesce with a buddy that fits.
+struct Blocks {
+    free: Vec<Vec<u64>>,
+    capacity: usize,
+}
+
+impl Blocks {
+    fn new(capacity: usize) -> Blocks {
+        let orders = (usize::BITS - capacity.leading_zeros()) as usize;
+
+        let mut blocks = Blocks {
+            free: (0..orders)
+                .map(|order| vec![0; (capacity >> order).div_ceil(BITS_IN_U64)])
+                .collect(),
+            capacity,
+        };
+
+        let mut offset = 0;
+
+        for order in (0..orders).rev() {
+            if capacity & (1 << order) != 0 {
+                blocks.set_free(order, offset >> order, true);
+                offset += 1 << order;
+            }
+        }
+
+        blocks
+    }
+
+    fn is_free(&self, order: usize, block: usize) -> bool {
+        self.free[order]
+            .get(block / BITS_IN_U64)
+            .is_some_and(|value| value & (1 << (block % BITS_IN_U64)) != 0)
+    }
+
+    fn set_free(&mut self, order: usize, block: usize, free: bool) {
+        let mask = 1 << (block % BITS_IN_U64);
+        let value = &mut self.free[order][block / BITS_IN_U64];
+
+        if free {
+            *value |= mask;
+        } else {
+            *value &= !mask;
+        }
+    }
+
+    fn find_free(&self, order: usize) -> Option<usize> {
+        self.free[order]
+            .iter()
+            .enumerate()
+            .find(|(_, value)| **value != 0)
+            .map(|(index, value)| index * BITS_IN_U64 + value.trailing_zeros() as usize)
+    }
+
+    /// Take a free block of the given order, splitting a larger block if
+    /// needed, and return its offset.
+    fn allocate(&mut self, order: usize) -> Option<usize> {
+        let (mut current, mut block) = (order..self.free.len())
+            .find_map(|current| self.find_free(current).map(|block| (current, block)))?;
+
+        self.set_free(current, block, false);
+
+        while current > order {
+            current -= 1;
+            block *= 2;
+            self.set_free(current, block + 1, true);
+        }
+
+        Some(block << order)
+    }
+
+    /// Return a block, merging it with its buddy for as long as the buddy is
+    /// also free.
+    fn free(&mut self, offset: usize, order: usize) {
+        let mut block = offset >> order;
+        let mut order = order;
+
+        while order + 1 < self.free.len() && self.is_free(order, block ^ 1) {
+            self.set_free(order, block ^ 1, false);
+            block /= 2;
+            order += 1;
+        }
+
+        self.set_free(order, block, true);
+    }
+
+    fn is_borrowed(&self) -> bool {
+        let mut free = 0;
+
+        for (order, values) in self.free.iter().enumerate() {
+            for value in values.iter() {
+                free += (value.count_ones() as usize) << order;
+            }
+        }
+
+        free != self.capacity
+    }
+}
+
+/// A `BufferPool` that hands out buffers of different lengths from one
+/// pre-allocated region, using a buddy allocator.
+///
+/// The region holds `capacity` blocks of `buffer_size` values. A request is
+/// rounded up to a power-of-two number of blocks, splitting larger blocks as
+/// needed, and blocks are merged back together as their references are
+/// dropped.
+pub struct BuddyBufferPool<V> {
+    buffer: Rc<RefCell<AlignedBuffer<V>>>,
+    init: Initializer<V>,
+    buffer_size: usize,
+    stride: usize,
+    blocks: Rc<RefCell<Blocks>>,
+}
+
+impl<V: Default> Default for BuddyBufferPool<V> {
+    fn default() -> BuddyBufferPool<V> {
+        BufferPoolBuilder::default().build_buddy()
+    }
+}
+
+impl<V: Default> BuddyBufferPool<V> {
+    pub fn builder() -> BufferPoolBuilder<V> {
+        BufferPoolBuilder::default()
+    }
+}
+
+impl<V> BuddyBufferPool<V> {
+    pub(crate) fn new(
+        buffer_size: usize,
+        capacity: usize,
+        align: usize,
+        init: Initializer<V>,
+    ) -> BuddyBufferPool<V> {
+        let stride = stride::<V>(buffer_size, align);
+        let mut buffer = AlignedBuffer::new(align);
+        buffer.resize_with(capacity * stride, || init.call());
+
+        BuddyBufferPool {
+            buffer: Rc::new(RefCell::new(buffer)),
+            init,
+            buffer_size,
+            stride,
+            blocks: Rc::new(RefCell::new(Blocks::new(capacity))),
+        }
+    }
+
+    /// Return the length of the smallest block
+    pub fn get_buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// The number of values from the start of one of the smallest blocks to
+    /// the start of the next. This is the buffer size plus any padding needed
+    /// for alignment.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Return the number of the smallest blocks that fit in the pool
+    pub fn capacity(&self) -> usize {
+        self.blocks.borrow().capacity
+    }
+
+    /// Check whether the buffer pool has no capacity
+    pub fn is_empty(&self) -> bool {
+        self.capacity() == 0
+    }
+
+    /// Checks to see whether any of the internal slices have been borrowed.
+    pub fn is_borrowed(&self) -> bool {
+        self.blocks.borrow().is_borrowed()
+    }
+
+    /// Get a reference to a slice of `buffer_size` values.
+    pub fn get_space(&mut self) -> Result<BuddyBufferPoolReference<V>, BufferPoolError> {
+        self.get_space_of_len(self.buffer_size)
+    }
+
+    /// Get a reference to a slice of `len` values setting the values back to
+    /// new values from the initializer.
+    pub fn get_cleared_space_of_len(
+        &mut self,
+        len: usize,
+    ) -> Result<BuddyBufferPoolReference<V>, BufferPoolError> {
+        self.get_space_of_len(len).map(|mut space| {
+            for value in space.as_mut().iter_mut() {
+                *value = self.init.call();
+            }
+
+            space
+        })
+    }
+
+    /// Get a reference to a slice of `len` values. The space is taken from a
+    /// block of `buffer_size` times the next power of two of the blocks needed.
+    pub fn get_space_of_len(
+        &mut self,
+        len: usize,
+    ) -> Result<BuddyBufferPoolReference<V>, BufferPoolError> {
+        let mut blocks = self.blocks.borrow_mut();
+
+        if len == 0 || self.buffer_size == 0 {
+            return Err(BufferPoolError::InvalidLength { len });
+        }
+
+        let order = len
+            .div_ceil(self.stride)
+            .next_power_of_two()
+            .trailing_zeros() as usize;
+
+        if order >= blocks.free.len() {
+            return Err(BufferPoolError::InvalidLength { len });
+        }
+
+        let offset = blocks.allocate(order).ok_or(BufferPoolError::Exhausted {
+            capacity: blocks.capacity,
+        })?;
+
+        let slice = unsafe {
+            (*self.buffer.borrow_mut())
+                .as_mut_ptr()
+                .add(offset * self.stride)
+        };
+
+        Ok(BuddyBufferPoolReference {
+            offset,
+            order,
+            blocks: Rc::clone(&self.blocks),
+            parent: Rc::clone(&self.buffer),
+            slice,
+            len,
+        })
+    }
+}
+
+/// A reference to a slice of the `BuddyBufferPool`.
+/// When dropped it will finish the borrow and return
+/// the block.
+pub struct BuddyBufferPoolReference<V> {
+    offset: usize,
+    order: usize,
+    blocks: Rc<RefCell<Blocks>>,
+    // This is only here so it will stay around
+    // after the parent is deallocated - never use
+    // it!
+    #[allow(dead_code)]
+    parent: Rc<RefCell<AlignedBuffer<V>>>,
+    slice: *mut V,
+    len: usize,
+}
+
+impl<V> AsMut<[V]> for BuddyBufferPoolReference<V> {
+    fn as_mut(&mut self) -> &mut [V] {
+        unsafe { alloc::slice::from_raw_parts_mut(self.slice, self.len) }
+    }
+}
+
+impl<V> AsRef<[V]> for BuddyBufferPoolReference<V> {
+    fn as_ref(&self) -> &[V] {
+        unsafe { alloc::slice::from_raw_parts(self.slice, self.len) }
+    }
+}
+
+impl<V> Drop for BuddyBufferPoolReference<V> {
+    fn drop(&mut self) {
+        self.blocks.borrow_mut().free(self.offset, self.order);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(capacity: usize) -> BuddyBufferPool<usize> {
+        BufferPoolBuilder::new()
+            .with_buffer_size(4)
+            .with_capacity(capacity)
+            .build_buddy()
+    }
+
+    #[test]
+    fn it_should_split_and_coalesce_blocks() {
+        let mut pool = pool(8);
+
+        let a = pool.get_space_of_len(1).unwrap();
+        let b = pool.get_space_of_len(9).unwrap();
+        let c = pool.get_space_of_len(8).unwrap();
+
+        assert_eq!((a.offset, a.order), (0, 0));
+        assert_eq!((b.offset, b.order), (4, 2));
+        assert_eq!((c.offset, c.order), (2, 1));
+        assert_eq!(b.as_ref().len(), 9);
+
+        assert!(pool.get_space_of_len(8).is_err());
+        assert_eq!(pool.get_space().unwrap().offset, 1);
+
+        drop(a);
+        drop(c);
+
+        assert_eq!(pool.get_space_of_len(16).unwrap().offset, 0);
+
+        drop(b);
+
+        assert!(!pool.is_borrowed());
+        assert_eq!(pool.get_space_of_len(32).unwrap().as_ref().len(), 32);
+    }
+
+    #[test]
+    fn it_should_use_capacities_that_are_not_a_power_of_two() {
+        let mut pool = pool(6);
+
+        assert_eq!(
+            pool.get_space_of_len(32).err(),
+            Some(BufferPoolError::InvalidLength { len: 32 })
+        );
+
+        let a = pool.get_space_of_len(16).unwrap();
+        let b = pool.get_space_of_len(8).unwrap();
+
+        assert_eq!((a.offset, b.offset), (0, 4));
+        assert_eq!(
+            pool.get_space().err(),
+            Some(BufferPoolError::Exhausted { capacity: 6 })
+        );
+
+        drop(b);
+        drop(a);
+
+        assert!(!pool.is_borrowed());
+        assert_eq!(pool.get_space_of_len(16).unwrap().offset, 0);
+    }
+
+    #[test]
+    fn it_should_not_overlap_blocks() {
+        let mut pool = pool(16);
+
+        let mut spaces: Vec<_> = [1, 3, 4, 5, 8, 12, 2, 16]
+            .iter()
+            .map(|len| pool.get_cleared_space_of_len(*len).unwrap())
+            .collect();
+
+        for (index, space) in spaces.iter_mut().enumerate() {
+            for value in space.as_mut().iter_mut() {
+                *value = index;
+            }
+        }
+
+        for (index, space) in spaces.iter().enumerate() {
+            assert!(space.as_ref().iter().all(|value| *value == index));
+        }
+
+        assert_eq!(
+            pool.get_space_of_len(0).err(),
+            Some(BufferPoolError::InvalidLength { len: 0 })
+        );
+    }
+
+    #[test]
+    fn it_should_align_every_block() {
+        let mut pool: BuddyBufferPool<u8> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(8)
+            .with_alignment(16)
+            .build_buddy();
+
+        assert_eq!(pool.stride(), 16);
+
+        let spaces: Vec<_> = [1, 16, 17, 10, 30]
+            .iter()
+            .map(|len| pool.get_space_of_len(*len).unwrap())
+            .collect();
+
+        for space in spaces.iter() {
+            assert_eq!(space.as_ref().as_ptr() as usize % 16, 0);
+        }
+
+        assert_eq!(spaces[1].order, 0);
+        assert_eq!(spaces[2].order, 1);
+    }
+
+    #[test]
+    fn it_should_fill_blocks_from_the_initializer() {
+        let mut pool: BuddyBufferPool<f32> = BufferPoolBuilder::with_fill_value(0.5)
+            .with_buffer_size(2)
+            .with_capacity(2)
+            .build_buddy();
+
+        let mut space = pool.get_space_of_len(4).unwrap();
+        assert_eq!(*space.as_ref(), [0.5; 4]);
+
+        space.as_mut().fill(1.);
+        drop(space);
+
+        assert_eq!(
+            *pool.get_cleared_space_of_len(4).unwrap().as_ref(),
+            [0.5; 4]
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use core::fmt;
+
+/// The reasons an operation on a buffer pool can fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BufferPoolError {
+    /// There aren't enough free buffers in the pool.
+    Exhausted { capacity: usize },
+    /// The operation can't run while buffers are borrowed.
+    Borrowed { borrowed: usize },
+    /// The index doesn't refer to a buffer in the pool.
+    IndexOutOfRange { index: usize, capacity: usize },
+    /// The pool can never hand out a buffer of the requested length.
+    InvalidLength { len: usize },
+    /// The pool can't start its buffers on a multiple of `align` bytes.
+    InvalidAlignment { align: usize },
+    /// Segmented storage needs room for at least one buffer in each segment.
+    EmptySegments,
+    /// The kind of pool being built doesn't support the option.
+    Unsupported { option: &'static str },
+    /// A call to the operating system failed with the given `errno`.
+    Os { operation: &'static str, errno: i32 },
+    /// No buffer became free before the timeout ran out.
+    Timeout,
+}
+
+impl BufferPoolError {
+    /// An `Os` error for the `errno` left behind by the failed operation.
+    #[cfg(any(feature = "mmap", feature = "realtime"))]
+    pub(crate) fn last_os_error(operation: &'static str) -> BufferPoolError {
+        BufferPoolError::Os {
+            operation,
+            errno: std::io::Error::last_os_error().raw_os_error().unwrap_or(0),
+        }
+    }
+}
+
+impl fmt::Display for BufferPoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BufferPoolError::Exhausted { capacity } => {
+                write!(f, "not enough free buffers in the pool of {}", capacity)
+            }
+            BufferPoolError::Borrowed { borrowed } => {
+                write!(f, "{} buffers are still borrowed", borrowed)
+            }
+            BufferPoolError::IndexOutOfRange { index, capacity } => write!(
+                f,
+                "index {} is out of range for a pool of {} buffers",
+                index, capacity
+            ),
+            BufferPoolError::InvalidLength { len } => {
+                write!(f, "the pool can't hand out a buffer of length {}", len)
+            }
+            BufferPoolError::InvalidAlignment { align } => {
+                write!(f, "the pool can't align buffers to {} bytes", align)
+            }
+            BufferPoolError::EmptySegments => {
+                write!(f, "segments must hold at least one buffer")
+            }
+            BufferPoolError::Unsupported { option } => {
+                write!(f, "the pool doesn't support the {} option", option)
+            }
+            BufferPoolError::Os { operation, errno } => {
+                write!(f, "{} failed (os error {})", operation, errno)
+            }
+            BufferPoolError::Timeout => write!(f, "timed out waiting for a free buffer"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BufferPoolError {}
@@ -0,0 +1,2120 @@
+use alloc::rc::Rc;
+use alloc::sync::Arc;
+use core::cell::{Cell, RefCell};
+use core::fmt;
+use core::future::Future;
+use core::ops::{Deref, DerefMut, Index, IndexMut};
+use core::pin::Pin;
+use core::ptr;
+use core::slice::{Iter, IterMut, SliceIndex};
+use core::sync::atomic::{compiler_fence, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use crate::aligned::{stride, CACHE_LINE};
+use crate::bitmap::Bitmap;
+#[cfg(feature = "realtime")]
+use crate::realtime::MemoryOptions;
+use crate::storage::{Initializer, Storage};
+use crate::{BuddyBufferPool, BufferPoolError, StorageLayout, SyncBufferPool};
+#[cfg(feature = "mmap")]
+use crate::{Pod, StorageBackend};
+
+/// A "vector of vectors" backed by a single contiguous vector.
+/// Allows for mutable borrows of non-overlapping regions.
+///
+/// The pool can instead be backed by fixed-size segments using
+/// `StorageLayout::Segmented`, which lets it grow while buffers are borrowed.
+///
+/// With the `mmap` feature, pools of `Pod` values can keep their buffers in a
+/// shared mapping using `BufferPoolBuilder::with_storage_backend`.
+pub struct BufferPool<V> {
+    buffer: Rc<RefCell<Storage<V>>>,
+    init: Initializer<V>,
+    buffer_size: usize,
+    align: usize,
+    used: Rc<RefCell<Bitmap>>,
+    waker: Rc<Cell<Option<Waker>>>,
+    growth: GrowthPolicy,
+    release: ReleasePolicy,
+    borrowed: Rc<Cell<usize>>,
+    peak_borrowed: usize,
+    acquisitions: u64,
+    exhaustions: u64,
+}
+
+/// What happens to the values of a buffer when it is returned to the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReleasePolicy {
+    /// The values are left as they are, until the buffer is handed out again.
+    #[default]
+    Keep,
+    /// The values are set back to new ones from the pool's initializer.
+    Reset,
+    /// The values are set back to new ones from the pool's initializer with
+    /// volatile writes that the compiler can't optimize away, for buffers that
+    /// held secrets. They are only zeroed if the initializer makes zeroes, as
+    /// `V::default()` does for numbers.
+    ResetVolatile,
+}
+
+impl ReleasePolicy {
+    /// Run the policy on the values of a buffer that is being returned.
+    pub(crate) fn run<V>(self, values: &mut [V], init: &Initializer<V>) {
+        match self {
+            ReleasePolicy::Keep => {}
+            ReleasePolicy::Reset => reset(values, init),
+            ReleasePolicy::ResetVolatile => reset_volatile(values, init),
+        }
+    }
+}
+
+fn reset<V>(values: &mut [V], init: &Initializer<V>) {
+    for value in values.iter_mut() {
+        *value = init.call();
+    }
+}
+
+fn reset_volatile<V>(values: &mut [V], init: &Initializer<V>) {
+    for value in values.iter_mut() {
+        let new = init.call();
+        let old = unsafe { ptr::read(value) };
+
+        unsafe { ptr::write_volatile(value, new) };
+        drop(old);
+    }
+
+    // Keep the writes from being reordered past the buffer being handed out
+    // again.
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// A snapshot of how a `BufferPool` has been used, from `BufferPool::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct BufferPoolStats {
+    /// The number of buffers that are borrowed.
+    pub borrowed: usize,
+    /// The number of buffers that are free.
+    pub free: usize,
+    /// The most buffers that have been borrowed at the same time.
+    pub peak_borrowed: usize,
+    /// The number of times space has been handed out.
+    pub acquisitions: u64,
+    /// The number of times space couldn't be handed out because the pool was
+    /// exhausted.
+    pub exhaustions: u64,
+    /// The number of bytes of memory holding the buffers.
+    pub reserved_bytes: usize,
+}
+
+/// How a `BufferPool` grows when all of its buffers are borrowed.
+///
+/// Only pools on the heap with a `StorageLayout::Segmented` layout can grow,
+/// as nothing else can make room while its buffers are borrowed. Building any
+/// other pool with a policy besides `Fixed` fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GrowthPolicy {
+    /// The pool never grows on its own.
+    #[default]
+    Fixed,
+    /// The pool grows by the given number of buffers.
+    By(usize),
+    /// The pool doubles its capacity.
+    Double,
+    /// The pool doubles its capacity, up to the given number of buffers. A
+    /// segmented pool stops at the last whole segment that fits.
+    UpTo(usize),
+}
+
+impl GrowthPolicy {
+    /// The capacity to grow a pool of `capacity` buffers to, if it can grow,
+    /// where the storage rounds capacities up to a multiple of `step`.
+    fn next_capacity(self, capacity: usize, step: usize) -> Option<usize> {
+        let doubled = capacity.saturating_mul(2).max(1);
+
+        match self {
+            GrowthPolicy::Fixed => None,
+            GrowthPolicy::By(additional) => capacity.checked_add(additional),
+            GrowthPolicy::Double => Some(doubled),
+            GrowthPolicy::UpTo(max) => Some(doubled.min(max - max % step)),
+        }
+        .filter(|next| *next > capacity)
+    }
+}
+
+/// A builder interface for creating a new `BufferPool`.
+pub struct BufferPoolBuilder<V> {
+    init: Initializer<V>,
+    buffer_size: usize,
+    capacity: usize,
+    align: usize,
+    cache_padded: bool,
+    layout: StorageLayout,
+    growth: GrowthPolicy,
+    release: ReleasePolicy,
+    #[cfg(feature = "mmap")]
+    backend: StorageBackend,
+    #[cfg(feature = "realtime")]
+    memory: MemoryOptions,
+}
+
+impl<V: Default> Default for BufferPoolBuilder<V> {
+    fn default() -> BufferPoolBuilder<V> {
+        BufferPoolBuilder::from_initializer(Initializer::Function(V::default))
+    }
+}
+
+impl<V: Default> BufferPoolBuilder<V> {
+    pub fn new() -> BufferPoolBuilder<V> {
+        BufferPoolBuilder::default()
+    }
+}
+
+impl<V> BufferPoolBuilder<V> {
+    /// Create a builder for a pool that fills its buffers with values from
+    /// `init` rather than `V::default()`, including when they are cleared, so
+    /// `V` needn't implement `Default`.
+    pub fn with_initializer(init: impl Fn() -> V + Send + Sync + 'static) -> BufferPoolBuilder<V> {
+        BufferPoolBuilder::from_initializer(Initializer::Closure(Arc::new(init)))
+    }
+
+    /// Create a builder for a pool that fills its buffers with clones of
+    /// `value` rather than `V::default()`, including when they are cleared.
+    pub fn with_fill_value(value: V) -> BufferPoolBuilder<V>
+    where
+        V: Clone + Send + Sync + 'static,
+    {
+        BufferPoolBuilder::with_initializer(move || value.clone())
+    }
+
+    fn from_initializer(init: Initializer<V>) -> BufferPoolBuilder<V> {
+        BufferPoolBuilder {
+            init,
+            buffer_size: 1024,
+            capacity: 0,
+            align: core::mem::align_of::<V>(),
+            cache_padded: false,
+            layout: StorageLayout::default(),
+            growth: GrowthPolicy::default(),
+            release: ReleasePolicy::default(),
+            #[cfg(feature = "mmap")]
+            backend: StorageBackend::default(),
+            #[cfg(feature = "realtime")]
+            memory: MemoryOptions::default(),
+        }
+    }
+
+    /// Set the capacity of the buffer pool - the max number of internal buffers.
+    pub fn with_capacity(mut self, capacity: usize) -> BufferPoolBuilder<V> {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set the buffer size / length of the internal buffers.
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> BufferPoolBuilder<V> {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Start every buffer on a multiple of `align` bytes, padding the space
+    /// between buffers as needed. Defaults to the alignment of `V`.
+    ///
+    /// # Panics
+    /// If `align` isn't a power of two
+    pub fn with_alignment(mut self, align: usize) -> BufferPoolBuilder<V> {
+        assert!(align.is_power_of_two(), "Alignment must be a power of two!");
+        self.align = align;
+        self
+    }
+
+    /// Pad every buffer to a whole number of cache lines and keep the record of
+    /// which buffers are borrowed on lines of its own, so threads writing to
+    /// different buffers never share a cache line.
+    pub fn with_cache_line_padding(mut self, cache_padded: bool) -> BufferPoolBuilder<V> {
+        self.cache_padded = cache_padded;
+        self
+    }
+
+    /// The alignment of each buffer, including any cache line padding.
+    fn align(&self) -> usize {
+        if self.cache_padded {
+            self.align.max(CACHE_LINE)
+        } else {
+            self.align
+        }
+    }
+
+    fn bitmap(&self, len: usize) -> Bitmap {
+        if self.cache_padded {
+            Bitmap::cache_padded(len)
+        } else {
+            Bitmap::new(len)
+        }
+    }
+
+    /// Set how the buffers are laid out in memory. Defaults to
+    /// `StorageLayout::Contiguous`.
+    pub fn with_storage_layout(mut self, layout: StorageLayout) -> BufferPoolBuilder<V> {
+        self.layout = layout;
+        self
+    }
+
+    /// Set how the pool grows when `get_space` finds all of the buffers
+    /// borrowed. Defaults to `GrowthPolicy::Fixed`.
+    ///
+    /// Only segmented pools on the heap can grow, so `try_build` rejects any
+    /// other policy for other pools.
+    pub fn with_growth_policy(mut self, growth: GrowthPolicy) -> BufferPoolBuilder<V> {
+        self.growth = growth;
+        self
+    }
+
+    /// Set what happens to the values of a buffer when it is returned to the
+    /// pool. Defaults to `ReleasePolicy::Keep`.
+    pub fn with_release_policy(mut self, release: ReleasePolicy) -> BufferPoolBuilder<V> {
+        self.release = release;
+        self
+    }
+
+    /// # Panics
+    /// If the storage can't be created
+    pub fn build(self) -> BufferPool<V> {
+        match self.try_build() {
+            Ok(pool) => pool,
+            Err(err) => panic!("Cannot build buffer pool: {}", err),
+        }
+    }
+
+    pub fn try_build(self) -> Result<BufferPool<V>, BufferPoolError> {
+        if self.layout == StorageLayout::Segmented(0) {
+            return Err(BufferPoolError::EmptySegments);
+        }
+
+        // Contiguous storage and mappings can only grow while none of their
+        // buffers are borrowed, and they're only ever grown when all of them
+        // are.
+        let segmented = matches!(self.layout, StorageLayout::Segmented(_));
+        #[cfg(feature = "mmap")]
+        let segmented = segmented && self.backend == StorageBackend::Heap;
+
+        if self.growth != GrowthPolicy::Fixed && !segmented {
+            return Err(BufferPoolError::Unsupported {
+                option: "growth policy",
+            });
+        }
+
+        let align = self.align();
+        let stride = stride::<V>(self.buffer_size, align);
+
+        #[cfg(feature = "mmap")]
+        let buffer = Storage::with_backend(
+            self.backend,
+            self.layout,
+            align,
+            self.capacity,
+            stride,
+            self.init.clone(),
+        )?;
+        #[cfg(not(feature = "mmap"))]
+        let buffer = Storage::new(self.layout, align, self.capacity, stride, self.init.clone());
+
+        #[cfg(feature = "realtime")]
+        let buffer = buffer.with_memory_options(self.memory)?;
+
+        let capacity = buffer.capacity(stride);
+
+        Ok(BufferPool {
+            buffer_size: self.buffer_size,
+            align,
+            buffer: Rc::new(RefCell::new(buffer)),
+            used: Rc::new(RefCell::new(self.bitmap(capacity))),
+            waker: Rc::new(Cell::new(None)),
+            growth: self.growth,
+            release: self.release,
+            borrowed: Rc::new(Cell::new(0)),
+            peak_borrowed: 0,
+            acquisitions: 0,
+            exhaustions: 0,
+            init: self.init,
+        })
+    }
+
+    /// Lock the memory of the pool into RAM with `mlock`, when it's built and
+    /// whenever it grows, so it's never paged out.
+    #[cfg(feature = "realtime")]
+    pub fn with_mlock(mut self, lock: bool) -> BufferPoolBuilder<V> {
+        self.memory.lock = lock;
+        self
+    }
+
+    /// Touch every page of the pool when it's built and whenever it grows, so
+    /// the first use of a buffer doesn't page fault.
+    #[cfg(feature = "realtime")]
+    pub fn with_prefault(mut self, prefault: bool) -> BufferPoolBuilder<V> {
+        self.memory.prefault = prefault;
+        self
+    }
+
+    /// Ask for the memory of the pool to be backed by transparent huge pages
+    /// with `madvise`.
+    #[cfg(feature = "realtime")]
+    pub fn with_huge_pages(mut self, huge_pages: bool) -> BufferPoolBuilder<V> {
+        self.memory.huge_pages = huge_pages;
+        self
+    }
+}
+
+impl<V> BufferPoolBuilder<V> {
+    /// Build a `SyncBufferPool` that can be shared between threads.
+    ///
+    /// # Panics
+    /// If the pool doesn't support the options or they can't be applied to
+    /// its memory
+    pub fn build_sync(self) -> SyncBufferPool<V> {
+        match self.try_build_sync() {
+            Ok(pool) => pool,
+            Err(err) => panic!("Cannot build sync buffer pool: {}", err),
+        }
+    }
+
+    /// Build a `SyncBufferPool` that can be shared between threads. Its
+    /// buffers always live on the heap and never grow, so other storage
+    /// backends and growth policies are rejected.
+    pub fn try_build_sync(self) -> Result<SyncBufferPool<V>, BufferPoolError> {
+        #[cfg(feature = "mmap")]
+        if self.backend != StorageBackend::Heap {
+            return Err(BufferPoolError::Unsupported {
+                option: "storage backend",
+            });
+        }
+
+        if self.growth != GrowthPolicy::Fixed {
+            return Err(BufferPoolError::Unsupported {
+                option: "growth policy",
+            });
+        }
+
+        let used = self.bitmap(self.capacity);
+
+        SyncBufferPool::new(
+            self.buffer_size,
+            self.align(),
+            used,
+            self.init,
+            self.release,
+            #[cfg(feature = "realtime")]
+            self.memory,
+        )
+    }
+
+    /// Build a `BuddyBufferPool` that hands out buffers of different lengths,
+    /// where the buffer size is the length of the smallest block.
+    pub fn build_buddy(self) -> BuddyBufferPool<V> {
+        BuddyBufferPool::new(self.buffer_size, self.capacity, self.align(), self.init)
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl<V: Pod> BufferPoolBuilder<V> {
+    /// Set where the memory for the buffers comes from. Defaults to
+    /// `StorageBackend::Heap`. Mapped backends ignore the storage layout,
+    /// keeping every buffer in a single mapping.
+    pub fn with_storage_backend(mut self, backend: StorageBackend) -> BufferPoolBuilder<V> {
+        self.backend = backend;
+        self
+    }
+}
+
+impl<V: Default> Default for BufferPool<V> {
+    fn default() -> BufferPool<V> {
+        BufferPoolBuilder::default().build()
+    }
+}
+
+impl<V: Default> BufferPool<V> {
+    pub fn builder() -> BufferPoolBuilder<V> {
+        BufferPoolBuilder::default()
+    }
+}
+
+impl<V> BufferPool<V> {
+    pub fn get_buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// The number of values from the start of one buffer to the start of the
+    /// next. This is the buffer size plus any padding needed for alignment.
+    pub fn stride(&self) -> usize {
+        stride::<V>(self.buffer_size, self.align)
+    }
+
+    /// Set all of the values back to new values from the initializer, which
+    /// defaults to `V::default()`
+    pub fn try_clear(&mut self) -> Result<(), BufferPoolError> {
+        self.ensure_not_borrowed()?;
+        self.buffer.borrow_mut().clear();
+        Ok(())
+    }
+
+    /// Set all of the values back to new values from the initializer, which
+    /// defaults to `V::default()`
+    ///
+    /// # Panics
+    /// If any of the buffers have been borrowed.
+    pub fn clear(&mut self) {
+        if let Err(err) = self.try_clear() {
+            panic!("Cannot clear buffer pool: {}", err);
+        }
+    }
+
+    fn find_free_index_and_use(&self) -> Result<usize, BufferPoolError> {
+        let used = self.used.borrow();
+
+        used.find_free_index_and_use()
+            .ok_or(BufferPoolError::Exhausted {
+                capacity: used.len(),
+            })
+    }
+
+    fn ensure_not_borrowed(&self) -> Result<(), BufferPoolError> {
+        let borrowed = self.used.borrow().count_used();
+
+        if borrowed == 0 {
+            Ok(())
+        } else {
+            Err(BufferPoolError::Borrowed { borrowed })
+        }
+    }
+
+    /// Return the max number of buffers
+    pub fn capacity(&self) -> usize {
+        self.buffer.borrow().capacity(self.stride())
+    }
+
+    /// Resize the internal buffers
+    ///
+    /// # Panics
+    /// If any of the buffers have been borrowed.
+    pub fn change_buffer_size(&mut self, new_buffer_size: usize) {
+        if let Err(err) = self.try_change_buffer_size(new_buffer_size) {
+            panic!("Cannot change buffer size to {}: {}", new_buffer_size, err);
+        }
+    }
+
+    /// Resize the internal buffers
+    pub fn try_change_buffer_size(
+        &mut self,
+        new_buffer_size: usize,
+    ) -> Result<(), BufferPoolError> {
+        self.ensure_not_borrowed()?;
+
+        let len = self.capacity();
+        let old_buffer_size = core::mem::replace(&mut self.buffer_size, new_buffer_size);
+
+        self.try_resize(len).inspect_err(|_| {
+            self.buffer_size = old_buffer_size;
+        })
+    }
+
+    /// Resize both the capacity and buffers
+    ///
+    /// # Panics
+    /// If any of the buffers have been borrowed
+    pub fn resize_len_and_buffer(&mut self, new_len: usize, new_buffer_size: usize) {
+        if let Err(err) = self.ensure_not_borrowed() {
+            panic!(
+                "Can't resize to {} buffers of {}: {}",
+                new_len, new_buffer_size, err
+            );
+        }
+
+        self.buffer_size = new_buffer_size;
+        self.resize(new_len);
+    }
+
+    /// Check whether the buffer pool has no capacity
+    pub fn is_empty(&self) -> bool {
+        self.capacity() == 0
+    }
+
+    /// Reserve an additional number of buffers
+    ///
+    /// # Panics
+    /// If any of the buffers have been borrowed
+    pub fn reserve(&mut self, additional: usize) {
+        self.resize(self.capacity() + additional);
+    }
+
+    /// Reserve an additional number of buffers. Segmented pools can be grown
+    /// while buffers are borrowed.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), BufferPoolError> {
+        self.try_resize(self.capacity() + additional)
+    }
+
+    /// Checks to see whether any of the internal slices have been borrowed.
+    pub fn is_borrowed(&self) -> bool {
+        self.used.borrow().any_used()
+    }
+
+    /// The file descriptor of the memfd the buffers live in, if the pool was
+    /// built with `StorageBackend::Memfd`. It stays owned by the pool and the
+    /// mapping may move when the pool is resized.
+    #[cfg(feature = "mmap")]
+    pub fn as_raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.buffer.borrow().fd()
+    }
+
+    /// Change the number of internal buffers
+    ///
+    /// # Panics
+    /// If any of the internal buffers have been borrowed, unless a segmented
+    /// pool is growing
+    pub fn resize(&mut self, new_len: usize) {
+        if let Err(err) = self.try_resize(new_len) {
+            panic!("Can't resize to {} buffers: {}", new_len, err);
+        }
+    }
+
+    /// Change the number of internal buffers. Segmented pools are rounded up
+    /// to a whole number of segments and can grow while buffers are borrowed.
+    pub fn try_resize(&mut self, new_len: usize) -> Result<(), BufferPoolError> {
+        if new_len < self.capacity() || !self.buffer.borrow().resizes_in_place() {
+            self.ensure_not_borrowed()?;
+        }
+
+        self.resize_storage(new_len)
+    }
+
+    /// Release the memory of the free buffers after the last borrowed one,
+    /// returning whether any was released. Segmented pools release whole
+    /// segments and pools in a mapping shrink it in place, even while earlier
+    /// buffers are borrowed. Contiguous pools on the heap only shrink once none
+    /// of the buffers are borrowed.
+    ///
+    /// # Panics
+    /// If the operating system fails to shrink a mapping
+    pub fn shrink_to_fit(&mut self) -> bool {
+        let len = self.used.borrow().last_used().map_or(0, |index| index + 1);
+        let capacity = self.capacity();
+
+        if len != 0 && !self.buffer.borrow().shrinks_in_place() {
+            return false;
+        }
+
+        if let Err(err) = self.resize_storage(len) {
+            panic!("Can't shrink to {} buffers: {}", len, err);
+        }
+
+        self.capacity() < capacity
+    }
+
+    /// Get a snapshot of how the pool has been used since it was built.
+    pub fn stats(&self) -> BufferPoolStats {
+        let borrowed = self.borrowed.get();
+        let capacity = self.capacity();
+
+        BufferPoolStats {
+            borrowed,
+            free: capacity - borrowed,
+            peak_borrowed: self.peak_borrowed,
+            acquisitions: self.acquisitions,
+            exhaustions: self.exhaustions,
+            reserved_bytes: capacity * self.stride() * core::mem::size_of::<V>(),
+        }
+    }
+
+    fn resize_storage(&mut self, new_len: usize) -> Result<(), BufferPoolError> {
+        let stride = self.stride();
+        let mut buffer = self.buffer.borrow_mut();
+
+        // The storage may have changed size even if it failed, so the bitmap
+        // is always brought in line with it.
+        let result = buffer.resize(new_len, stride);
+        self.used.borrow_mut().resize(buffer.capacity(stride));
+
+        result
+    }
+
+    /// Get a reference to a slice of the `BufferPool` setting the values of the
+    /// pool back to new values from the initializer.
+    pub fn get_cleared_space(&mut self) -> Result<BufferPoolReference<V>, BufferPoolError> {
+        self.get_space().map(|mut space| {
+            space.reset();
+            space
+        })
+    }
+
+    /// Get a reference to a slice of the `BufferPool`, growing the pool by its
+    /// growth policy if all of the buffers are borrowed.
+    pub fn get_space(&mut self) -> Result<BufferPoolReference<V>, BufferPoolError> {
+        let index = self.find_free_index_or_grow();
+        self.reference(index, 1)
+    }
+
+    /// Mark a free buffer as used, growing the pool by its growth policy if
+    /// there are none.
+    fn find_free_index_or_grow(&mut self) -> Result<usize, BufferPoolError> {
+        self.find_free_index_and_use().or_else(|err| {
+            let step = self.buffer.borrow().capacity_step();
+            let grown = self
+                .growth
+                .next_capacity(self.capacity(), step)
+                .is_some_and(|capacity| self.try_resize(capacity).is_ok());
+
+            if grown {
+                self.find_free_index_and_use()
+            } else {
+                Err(err)
+            }
+        })
+    }
+
+    /// Get a future that resolves to a reference to a slice of the
+    /// `BufferPool` once a buffer is free. It fails straight away if the pool
+    /// is exhausted and none of the buffers are borrowed.
+    pub fn acquire(&mut self) -> Acquire<'_, V> {
+        Acquire {
+            pool: self,
+            waited: false,
+        }
+    }
+
+    /// Get a reference to `count` neighbouring buffers of the `BufferPool` as a
+    /// single slice, from the start of the first buffer to the end of the last
+    /// one. Dropping it returns all of the buffers.
+    ///
+    /// Segmented pools can't hand out more buffers than fit in one segment.
+    pub fn get_contiguous_space(
+        &mut self,
+        count: usize,
+    ) -> Result<BufferPoolReference<V>, BufferPoolError> {
+        if count == 0 || count > self.buffer.borrow().max_contiguous_buffers() {
+            return Err(BufferPoolError::InvalidLength {
+                len: count * self.buffer_size,
+            });
+        }
+
+        let index = {
+            let used = self.used.borrow();
+            let boundary = self.buffer.borrow().max_contiguous_buffers();
+
+            used.find_free_range_and_use(count, boundary)
+                .ok_or(BufferPoolError::Exhausted {
+                    capacity: used.len(),
+                })
+        };
+
+        self.reference(index, count)
+    }
+
+    /// Hand out the `count` buffers from `index` that were just marked as
+    /// used, keeping track of the result in the stats.
+    fn reference(
+        &mut self,
+        index: Result<usize, BufferPoolError>,
+        count: usize,
+    ) -> Result<BufferPoolReference<V>, BufferPoolError> {
+        let index = index.inspect_err(|err| {
+            if let BufferPoolError::Exhausted { .. } = err {
+                self.exhaustions += 1;
+            }
+        })?;
+
+        let borrowed = self.borrowed.get() + count;
+        self.borrowed.set(borrowed);
+        self.peak_borrowed = self.peak_borrowed.max(borrowed);
+        self.acquisitions += 1;
+
+        let stride = self.stride();
+        let slice = self.buffer.borrow_mut().as_mut_ptr(index, stride);
+        let len = (count - 1) * stride + self.buffer_size;
+
+        Ok(BufferPoolReference {
+            lease: Rc::new(Lease {
+                index,
+                count,
+                slice,
+                len,
+                release: self.release,
+                init: self.init.clone(),
+                used: Rc::clone(&self.used),
+                waker: Rc::clone(&self.waker),
+                borrowed: Rc::clone(&self.borrowed),
+                parent: Rc::clone(&self.buffer),
+            }),
+            len,
+            slice,
+        })
+    }
+}
+
+/// The buffers handed out to a `BufferPoolReference`. They are returned to
+/// the pool once every reference sharing the lease is dropped.
+struct Lease<V> {
+    index: usize,
+    count: usize,
+    slice: *mut V,
+    len: usize,
+    release: ReleasePolicy,
+    init: Initializer<V>,
+    used: Rc<RefCell<Bitmap>>,
+    waker: Rc<Cell<Option<Waker>>>,
+    borrowed: Rc<Cell<usize>>,
+    // This is only here so it will stay around
+    // after the parent is deallocated - never use
+    // it!
+    #[allow(dead_code)]
+    parent: Rc<RefCell<Storage<V>>>,
+}
+
+impl<V> Drop for Lease<V> {
+    fn drop(&mut self) {
+        if self.release != ReleasePolicy::Keep {
+            let values = unsafe { alloc::slice::from_raw_parts_mut(self.slice, self.len) };
+            self.release.run(values, &self.init);
+        }
+
+        let used = self.used.borrow();
+
+        if let Err(err) = used.set_range_free(self.index, self.count) {
+            panic!("Unable to free reference: {}", err);
+        }
+
+        self.borrowed.set(self.borrowed.get() - self.count);
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A reference to a slice of the `BufferPool`.
+/// When dropped it will finish the borrow and return
+/// the space.
+pub struct BufferPoolReference<V> {
+    lease: Rc<Lease<V>>,
+    slice: *mut V,
+    pub(crate) len: usize,
+}
+
+impl<V> BufferPoolReference<V> {
+    /// Split the reference into one for the values before `mid` and one for
+    /// the values from `mid` on. The space is returned to the pool once both
+    /// halves are dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mid > len`.
+    pub fn split_at(self, mid: usize) -> (BufferPoolReference<V>, BufferPoolReference<V>) {
+        assert!(mid <= self.len, "Split point is out of bounds!");
+
+        let right = BufferPoolReference {
+            lease: Rc::clone(&self.lease),
+            slice: unsafe { self.slice.add(mid) },
+            len: self.len - mid,
+        };
+
+        let left = BufferPoolReference { len: mid, ..self };
+
+        (left, right)
+    }
+
+    /// Turn the reference into a read-only one that can be cloned. The space
+    /// is returned to the pool once every clone is dropped.
+    pub fn freeze(self) -> SharedBufferRef<V> {
+        SharedBufferRef {
+            reference: Rc::new(self),
+        }
+    }
+
+    /// Set the values back to new values from the initializer of the pool.
+    pub(crate) fn reset(&mut self) {
+        let init = self.lease.init.clone();
+        reset(self, &init);
+    }
+}
+
+impl<V> AsMut<[V]> for BufferPoolReference<V> {
+    fn as_mut(&mut self) -> &mut [V] {
+        unsafe { alloc::slice::from_raw_parts_mut(self.slice, self.len) }
+    }
+}
+
+impl<V> AsRef<[V]> for BufferPoolReference<V> {
+    fn as_ref(&self) -> &[V] {
+        unsafe { alloc::slice::from_raw_parts(self.slice, self.len) }
+    }
+}
+
+impl<V> Deref for BufferPoolReference<V> {
+    type Target = [V];
+
+    fn deref(&self) -> &[V] {
+        self.as_ref()
+    }
+}
+
+impl<V> DerefMut for BufferPoolReference<V> {
+    fn deref_mut(&mut self) -> &mut [V] {
+        self.as_mut()
+    }
+}
+
+impl<V> core::borrow::Borrow<[V]> for BufferPoolReference<V> {
+    fn borrow(&self) -> &[V] {
+        self
+    }
+}
+
+impl<V> core::borrow::BorrowMut<[V]> for BufferPoolReference<V> {
+    fn borrow_mut(&mut self) -> &mut [V] {
+        self
+    }
+}
+
+impl<V, I: SliceIndex<[V]>> Index<I> for BufferPoolReference<V> {
+    type Output = I::Output;
+
+    fn index(&self, index: I) -> &I::Output {
+        &(**self)[index]
+    }
+}
+
+impl<V, I: SliceIndex<[V]>> IndexMut<I> for BufferPoolReference<V> {
+    fn index_mut(&mut self, index: I) -> &mut I::Output {
+        &mut (**self)[index]
+    }
+}
+
+impl<'a, V> IntoIterator for &'a BufferPoolReference<V> {
+    type Item = &'a V;
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Iter<'a, V> {
+        self.iter()
+    }
+}
+
+impl<'a, V> IntoIterator for &'a mut BufferPoolReference<V> {
+    type Item = &'a mut V;
+    type IntoIter = IterMut<'a, V>;
+
+    fn into_iter(self) -> IterMut<'a, V> {
+        self.iter_mut()
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for BufferPoolReference<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<V: PartialEq> PartialEq for BufferPoolReference<V> {
+    fn eq(&self, other: &BufferPoolReference<V>) -> bool {
+        **self == **other
+    }
+}
+
+impl<V: Eq> Eq for BufferPoolReference<V> {}
+
+impl<V: PartialEq> PartialEq<[V]> for BufferPoolReference<V> {
+    fn eq(&self, other: &[V]) -> bool {
+        **self == *other
+    }
+}
+
+/// The future returned by `BufferPool::acquire`.
+pub struct Acquire<'a, V> {
+    pool: &'a mut BufferPool<V>,
+    waited: bool,
+}
+
+impl<V> Future for Acquire<'_, V> {
+    type Output = Result<BufferPoolReference<V>, BufferPoolError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let acquire = self.get_mut();
+        let pool = &mut *acquire.pool;
+
+        // The waker is registered before looking for a buffer, so a buffer
+        // that is returned in between still wakes the task.
+        pool.waker.set(Some(cx.waker().clone()));
+
+        let index = pool.find_free_index_or_grow();
+
+        if let Err(BufferPoolError::Exhausted { .. }) = index {
+            if pool.is_borrowed() {
+                // Waiting counts as a single exhaustion, however often the
+                // task is polled.
+                if !acquire.waited {
+                    acquire.waited = true;
+                    pool.exhaustions += 1;
+                }
+
+                return Poll::Pending;
+            }
+        }
+
+        pool.waker.set(None);
+
+        match index {
+            Err(err) if acquire.waited => Poll::Ready(Err(err)),
+            index => Poll::Ready(pool.reference(index, 1)),
+        }
+    }
+}
+
+/// A read-only reference to a slice of the `BufferPool` that can be cloned.
+/// When the last clone is dropped it will return the space.
+pub struct SharedBufferRef<V> {
+    reference: Rc<BufferPoolReference<V>>,
+}
+
+impl<V> SharedBufferRef<V> {
+    /// Get the mutable reference back, or the shared reference if it still
+    /// has other clones.
+    pub fn try_unfreeze(self) -> Result<BufferPoolReference<V>, SharedBufferRef<V>> {
+        Rc::try_unwrap(self.reference).map_err(|reference| SharedBufferRef { reference })
+    }
+}
+
+impl<V> Clone for SharedBufferRef<V> {
+    fn clone(&self) -> SharedBufferRef<V> {
+        SharedBufferRef {
+            reference: Rc::clone(&self.reference),
+        }
+    }
+}
+
+impl<V> AsRef<[V]> for SharedBufferRef<V> {
+    fn as_ref(&self) -> &[V] {
+        &self.reference
+    }
+}
+
+impl<V> Deref for SharedBufferRef<V> {
+    type Target = [V];
+
+    fn deref(&self) -> &[V] {
+        &self.reference
+    }
+}
+
+impl<V> core::borrow::Borrow<[V]> for SharedBufferRef<V> {
+    fn borrow(&self) -> &[V] {
+        self
+    }
+}
+
+impl<'a, V> IntoIterator for &'a SharedBufferRef<V> {
+    type Item = &'a V;
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Iter<'a, V> {
+        self.iter()
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for SharedBufferRef<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<V: PartialEq> PartialEq for SharedBufferRef<V> {
+    fn eq(&self, other: &SharedBufferRef<V>) -> bool {
+        **self == **other
+    }
+}
+
+impl<V: Eq> Eq for SharedBufferRef<V> {}
+
+impl<V: PartialEq> PartialEq<[V]> for SharedBufferRef<V> {
+    fn eq(&self, other: &[V]) -> bool {
+        **self == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_add_capacity() {
+        let mut pool: BufferPool<f32> = BufferPool::default();
+
+        assert_eq!(pool.capacity(), 0);
+
+        pool.reserve(1);
+
+        assert_eq!(pool.capacity(), 1);
+
+        pool.reserve(1);
+
+        assert_eq!(pool.capacity(), 2);
+    }
+
+    #[test]
+    fn it_should_get_space_if_capacity() {
+        let mut pool: BufferPool<f32> = BufferPool::default();
+
+        assert_eq!(pool.capacity(), 0);
+
+        assert!(pool.get_space().is_err());
+
+        pool.resize(1);
+
+        let index = pool.get_space().unwrap();
+
+        assert!(pool.get_space().is_err());
+        assert_eq!(index.lease.index, 0);
+    }
+
+    #[test]
+    fn it_should_work_with_interesting_sizes() {
+        let sizes: Vec<usize> = vec![12, 100, 1001, 1024, 2048, 4096, 1];
+
+        for buffer_size in sizes.iter() {
+            for capacity in sizes.iter() {
+                let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+                    .with_buffer_size(*buffer_size)
+                    .with_capacity(*capacity)
+                    .build();
+
+                assert_eq!(pool.capacity(), *capacity);
+                assert!(pool.get_space().is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_return_space_when_deallocated() {
+        let mut pool: BufferPool<f32> = BufferPool::default();
+
+        assert_eq!(pool.capacity(), 0);
+        pool.reserve(1);
+
+        {
+            let index = pool.get_space().unwrap();
+            assert!(pool.get_space().is_err());
+            assert_eq!(index.lease.index, 0);
+        }
+
+        assert!(pool.get_space().is_ok());
+    }
+
+    #[test]
+    fn it_should_update_internal_buffer() {
+        let buffer_size = 10;
+        let mut pool: BufferPool<f32> = BufferPool::default();
+        pool.change_buffer_size(buffer_size);
+        pool.reserve(10);
+
+        let mut a = pool.get_space().unwrap();
+        let mut b = pool.get_space().unwrap();
+
+        for value in a.as_mut().iter_mut() {
+            *value = 1.;
+        }
+
+        for value in b.as_mut().iter_mut() {
+            *value = 2.;
+        }
+
+        let buffer = pool.buffer.borrow();
+        assert_eq!((*buffer)[0..(buffer_size)], vec![1_f32; buffer_size][..]);
+
+        assert_eq!(*a.as_ref(), vec![1_f32; buffer_size][..]);
+
+        let buffer = pool.buffer.borrow();
+        assert_eq!(
+            (*buffer)[(buffer_size)..(2 * buffer_size)],
+            vec![2_f32; buffer_size][..]
+        );
+
+        assert_eq!(*b.as_ref(), vec![2_f32; buffer_size][..]);
+    }
+
+    #[test]
+    fn it_should_run_the_release_policy_when_space_is_returned() {
+        for release in [ReleasePolicy::Reset, ReleasePolicy::ResetVolatile] {
+            let mut pool: BufferPool<u8> = BufferPoolBuilder::new()
+                .with_buffer_size(4)
+                .with_capacity(2)
+                .with_release_policy(release)
+                .build();
+
+            let mut a = pool.get_contiguous_space(2).unwrap();
+            a.fill(0xff);
+
+            let (left, right) = a.split_at(1);
+            drop(left);
+            assert_eq!(pool.buffer.borrow()[0..8], [0xff; 8]);
+
+            drop(right);
+            assert_eq!(pool.buffer.borrow()[0..8], [0; 8]);
+        }
+    }
+
+    #[test]
+    fn it_should_drop_values_when_resetting_them_volatile() {
+        let value = Rc::new(());
+        let mut pool: BufferPool<Option<Rc<()>>> = BufferPoolBuilder::new()
+            .with_buffer_size(4)
+            .with_capacity(1)
+            .with_release_policy(ReleasePolicy::ResetVolatile)
+            .build();
+
+        pool.get_space().unwrap().fill(Some(Rc::clone(&value)));
+
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn it_should_reset_values_volatile_to_the_fill_value() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::with_fill_value(0.5)
+            .with_buffer_size(2)
+            .with_capacity(1)
+            .with_release_policy(ReleasePolicy::ResetVolatile)
+            .build();
+
+        pool.get_space().unwrap().fill(1.);
+
+        assert_eq!(pool.buffer.borrow()[0..2], [0.5, 0.5]);
+    }
+
+    #[test]
+    fn it_should_not_default_space_when_deallocated() {
+        let buffer_size = 10;
+        let mut pool: BufferPool<f32> = BufferPool::default();
+        pool.change_buffer_size(buffer_size);
+        pool.reserve(10);
+
+        {
+            let mut a = pool.get_space().unwrap();
+
+            for value in a.as_mut().iter_mut() {
+                *value = 1.;
+            }
+
+            let buffer = pool.buffer.borrow();
+            assert_eq!((*buffer)[0..(buffer_size)], vec![1_f32; buffer_size][..]);
+
+            assert_eq!(*a.as_ref(), vec![1_f32; buffer_size][..]);
+        }
+
+        let buffer = pool.buffer.borrow();
+
+        assert_eq!((*buffer)[0..(buffer_size)], vec![1_f32; buffer_size][..]);
+    }
+
+    #[test]
+    fn it_should_clear_space_if_explicitly_requested() {
+        let buffer_size = 10;
+        let mut pool: BufferPool<f32> = BufferPool::default();
+        pool.change_buffer_size(buffer_size);
+        pool.reserve(10);
+
+        {
+            let mut a = pool.get_space().unwrap();
+
+            for value in a.as_mut().iter_mut() {
+                *value = 1.;
+            }
+
+            let buffer = pool.buffer.borrow();
+
+            assert_eq!((*buffer)[0..(buffer_size)], vec![1_f32; buffer_size][..]);
+
+            assert_eq!(*a.as_ref(), vec![1_f32; buffer_size][..]);
+        }
+
+        let space = pool.get_cleared_space().unwrap();
+
+        let buffer = pool.buffer.borrow();
+
+        assert_eq!((*buffer)[0..(buffer_size)], vec![0_f32; buffer_size][..]);
+
+        assert_eq!(*space.as_ref(), vec![0_f32; buffer_size][..]);
+    }
+
+    #[test]
+    fn it_should_still_work_if_parent_is_dropped() {
+        let buffer_size = 10;
+        let mut pool: BufferPool<usize> = BufferPool::default();
+        pool.change_buffer_size(buffer_size);
+        pool.reserve(10);
+
+        let space = pool.get_cleared_space().unwrap();
+
+        drop(pool);
+
+        let value: usize = space.as_ref().iter().sum();
+        assert_eq!(value, 0);
+    }
+
+    #[test]
+    fn it_should_only_be_borrowed_while_references_are_alive() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(100)
+            .build();
+
+        assert!(!pool.is_borrowed());
+
+        let mut spaces = Vec::new();
+
+        for _ in 0..70 {
+            spaces.push(pool.get_space().unwrap());
+        }
+
+        let last = spaces.pop().unwrap();
+        spaces.clear();
+
+        assert!(pool.is_borrowed());
+
+        drop(last);
+
+        assert!(!pool.is_borrowed());
+    }
+
+    #[test]
+    fn it_should_not_reserve_while_borrowed() {
+        let buffer_size = 10;
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(buffer_size)
+            .with_capacity(1)
+            .build();
+
+        let mut space = pool.get_space().unwrap();
+
+        assert!(pool.try_reserve(1000).is_err());
+        assert_eq!(pool.capacity(), 1);
+
+        for value in space.as_mut().iter_mut() {
+            *value = 1.;
+        }
+
+        assert_eq!(*space.as_ref(), vec![1_f32; buffer_size][..]);
+
+        drop(space);
+
+        assert!(pool.try_reserve(1000).is_ok());
+        assert_eq!(pool.capacity(), 1001);
+    }
+
+    #[test]
+    fn it_should_report_why_it_failed() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(2)
+            .build();
+
+        let _a = pool.get_space().unwrap();
+        let _b = pool.get_space().unwrap();
+
+        assert_eq!(
+            pool.get_space().err(),
+            Some(BufferPoolError::Exhausted { capacity: 2 })
+        );
+
+        assert_eq!(
+            pool.try_reserve(1),
+            Err(BufferPoolError::Borrowed { borrowed: 2 })
+        );
+
+        assert_eq!(
+            pool.try_reserve(1).unwrap_err().to_string(),
+            "2 buffers are still borrowed"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't resize to 3 buffers: 1 buffers are still borrowed")]
+    fn it_should_include_the_error_when_panicking() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(2)
+            .build();
+
+        let _space = pool.get_space().unwrap();
+
+        pool.reserve(1);
+    }
+
+    #[test]
+    fn it_should_not_resize_clear_or_change_buffer_size_while_borrowed() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(2)
+            .build();
+
+        let space = pool.get_space().unwrap();
+
+        assert!(pool.try_resize(0).is_err());
+        assert!(pool.try_clear().is_err());
+        assert!(pool.try_change_buffer_size(20).is_err());
+        assert_eq!(pool.get_buffer_size(), 10);
+        assert_eq!(pool.capacity(), 2);
+
+        drop(space);
+
+        assert!(pool.try_change_buffer_size(20).is_ok());
+        assert!(pool.try_clear().is_ok());
+        assert!(pool.try_resize(0).is_ok());
+    }
+
+    #[test]
+    #[should_panic]
+    fn it_should_panic_when_reserving_while_borrowed() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(1)
+            .build();
+
+        let _space = pool.get_space().unwrap();
+
+        pool.reserve(1);
+    }
+
+    #[test]
+    fn it_should_reserve_segments_while_borrowed() {
+        let buffer_size = 10;
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(buffer_size)
+            .with_capacity(1)
+            .with_storage_layout(StorageLayout::Segmented(2))
+            .build();
+
+        assert_eq!(pool.capacity(), 2);
+
+        let mut spaces = Vec::new();
+
+        for index in 0..100 {
+            let mut space = pool.get_space().or_else(|_| {
+                pool.reserve(1);
+                pool.get_space()
+            });
+
+            for value in space.as_mut().unwrap().as_mut().iter_mut() {
+                *value = index as f32;
+            }
+
+            spaces.push(space.unwrap());
+        }
+
+        assert_eq!(pool.capacity(), 100);
+        assert!(pool.get_space().is_err());
+
+        for (index, space) in spaces.iter().enumerate() {
+            assert_eq!(*space.as_ref(), vec![index as f32; buffer_size][..]);
+        }
+
+        let buffer = pool.buffer.borrow();
+        assert_eq!(
+            (*buffer)[(99 * buffer_size)..(100 * buffer_size)],
+            vec![99_f32; buffer_size][..]
+        );
+    }
+
+    #[test]
+    fn it_should_not_shrink_segments_while_borrowed() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(4)
+            .with_storage_layout(StorageLayout::Segmented(2))
+            .build();
+
+        let space = pool.get_space().unwrap();
+
+        assert!(pool.try_resize(2).is_err());
+        assert!(pool.try_change_buffer_size(20).is_err());
+        assert!(pool.try_clear().is_err());
+
+        drop(space);
+
+        assert!(pool.try_resize(2).is_ok());
+        assert_eq!(pool.capacity(), 2);
+    }
+
+    #[test]
+    fn it_should_not_build_segments_without_buffers() {
+        let result: Result<BufferPool<f32>, _> = BufferPoolBuilder::new()
+            .with_capacity(2)
+            .with_storage_layout(StorageLayout::Segmented(0))
+            .try_build();
+
+        assert_eq!(result.err(), Some(BufferPoolError::EmptySegments));
+    }
+
+    #[test]
+    fn it_should_shrink_segments_after_the_last_borrowed_buffer() {
+        let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(8)
+            .with_storage_layout(StorageLayout::Segmented(2))
+            .build();
+
+        let mut spaces: Vec<_> = (0..4).map(|_| pool.get_space().unwrap()).collect();
+        spaces[2][0] = 2;
+        spaces.truncate(3);
+
+        assert!(pool.shrink_to_fit());
+        assert_eq!(pool.capacity(), 4);
+        assert_eq!(spaces[2][0], 2);
+
+        spaces.remove(2);
+        spaces.remove(1);
+
+        assert!(pool.shrink_to_fit());
+        assert_eq!(pool.capacity(), 2);
+        assert_eq!(pool.used.borrow().len(), 2);
+
+        assert!(!pool.shrink_to_fit());
+
+        spaces.clear();
+
+        assert!(pool.shrink_to_fit());
+        assert_eq!(pool.capacity(), 0);
+    }
+
+    #[test]
+    fn it_should_only_shrink_contiguous_pools_when_nothing_is_borrowed() {
+        let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(4)
+            .build();
+
+        let space = pool.get_space().unwrap();
+
+        assert!(!pool.shrink_to_fit());
+        assert_eq!(pool.capacity(), 4);
+
+        drop(space);
+
+        assert!(pool.shrink_to_fit());
+        assert_eq!(pool.capacity(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn it_should_shrink_mappings_after_the_last_borrowed_buffer() {
+        for backend in [StorageBackend::Anonymous, StorageBackend::Memfd] {
+            let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+                .with_buffer_size(1024)
+                .with_capacity(8)
+                .with_storage_backend(backend)
+                .build();
+
+            let mut spaces: Vec<_> = (0..3).map(|_| pool.get_space().unwrap()).collect();
+            spaces[1].fill(1);
+            spaces.truncate(2);
+
+            assert!(pool.shrink_to_fit());
+            assert_eq!(pool.capacity(), 2);
+            assert_eq!(pool.used.borrow().len(), 2);
+            assert!(spaces[1].iter().all(|value| *value == 1));
+
+            spaces[1].fill(2);
+            assert_eq!(pool.buffer.borrow()[1024..1026], [2, 2]);
+        }
+    }
+
+    #[test]
+    fn it_should_get_contiguous_space() {
+        let buffer_size = 10;
+        let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+            .with_buffer_size(buffer_size)
+            .with_capacity(8)
+            .build();
+
+        let a = pool.get_space().unwrap();
+        let mut span = pool.get_contiguous_space(4).unwrap();
+
+        assert_eq!(span.lease.index, 1);
+        assert_eq!(span.as_ref().len(), 4 * buffer_size);
+
+        for (index, value) in span.as_mut().iter_mut().enumerate() {
+            *value = index;
+        }
+
+        {
+            let buffer = pool.buffer.borrow();
+            let expected: Vec<usize> = (0..(4 * buffer_size)).collect();
+            assert_eq!((*buffer)[buffer_size..(5 * buffer_size)], expected[..]);
+        }
+
+        assert!(pool.get_contiguous_space(4).is_err());
+        let b = pool.get_contiguous_space(3).unwrap();
+        assert_eq!(b.lease.index, 5);
+
+        drop(a);
+
+        assert_eq!(
+            pool.get_contiguous_space(2).err(),
+            Some(BufferPoolError::Exhausted { capacity: 8 })
+        );
+
+        drop(span);
+        drop(b);
+
+        assert_eq!(pool.get_contiguous_space(8).unwrap().lease.index, 0);
+        assert!(!pool.is_borrowed());
+    }
+
+    #[test]
+    fn it_should_keep_contiguous_space_inside_a_segment() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(8)
+            .with_storage_layout(StorageLayout::Segmented(4))
+            .build();
+
+        let _a = pool.get_space().unwrap();
+
+        assert_eq!(pool.get_contiguous_space(4).unwrap().lease.index, 4);
+        assert_eq!(
+            pool.get_contiguous_space(5).err(),
+            Some(BufferPoolError::InvalidLength { len: 50 })
+        );
+        assert_eq!(
+            pool.get_contiguous_space(0).err(),
+            Some(BufferPoolError::InvalidLength { len: 0 })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn it_should_keep_buffers_in_a_memfd() {
+        let buffer_size = 4;
+        let mut pool: BufferPool<u32> = BufferPoolBuilder::new()
+            .with_buffer_size(buffer_size)
+            .with_capacity(2)
+            .with_storage_backend(StorageBackend::Memfd)
+            .build();
+
+        let fd = pool.as_raw_fd().unwrap();
+
+        let a = pool.get_cleared_space().unwrap();
+        let mut b = pool.get_space().unwrap();
+        b.as_mut().copy_from_slice(&[1, 2, 3, 4]);
+
+        let mut values = [0_u32; 8];
+        let read = unsafe { libc::pread(fd, values.as_mut_ptr().cast(), 32, 0) };
+
+        assert_eq!(read, 32);
+        assert_eq!(values, [0, 0, 0, 0, 1, 2, 3, 4]);
+
+        drop(a);
+        drop(b);
+
+        pool.reserve(1000);
+        assert_eq!(pool.capacity(), 1002);
+        assert_eq!(pool.buffer.borrow()[4..8], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn it_should_keep_buffers_in_an_anonymous_mapping() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(10)
+            .with_storage_layout(StorageLayout::Segmented(2))
+            .with_storage_backend(StorageBackend::Anonymous)
+            .try_build()
+            .unwrap();
+
+        assert!(pool.as_raw_fd().is_none());
+        assert_eq!(pool.capacity(), 10);
+        assert_eq!(*pool.get_space().unwrap().as_ref(), [0_f32; 10]);
+        assert_eq!(pool.get_contiguous_space(9).unwrap().as_ref().len(), 90);
+
+        pool.resize(0);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn it_should_fill_mappings_from_the_initializer() {
+        for backend in [StorageBackend::Anonymous, StorageBackend::Memfd] {
+            let mut pool: BufferPool<f32> = BufferPoolBuilder::with_fill_value(0.5)
+                .with_buffer_size(2)
+                .with_capacity(1)
+                .with_storage_backend(backend)
+                .build();
+
+            pool.get_space().unwrap().fill(1.);
+            pool.reserve(1000);
+
+            assert_eq!(pool.buffer.borrow()[0..4], [1., 1., 0.5, 0.5]);
+            assert_eq!(pool.buffer.borrow()[2000..2002], [0.5, 0.5]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn it_should_not_align_mappings_past_a_page() {
+        let result: Result<BufferPool<u8>, _> = BufferPoolBuilder::new()
+            .with_alignment(1 << 16)
+            .with_storage_backend(StorageBackend::Anonymous)
+            .try_build();
+
+        assert_eq!(
+            result.err(),
+            Some(BufferPoolError::InvalidAlignment { align: 1 << 16 })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "realtime")]
+    fn it_should_lock_and_prefault_memory() {
+        let mut pool: BufferPool<u8> = BufferPoolBuilder::new()
+            .with_buffer_size(4096)
+            .with_capacity(4)
+            .with_storage_layout(StorageLayout::Segmented(2))
+            .with_mlock(true)
+            .with_prefault(true)
+            .with_huge_pages(true)
+            .try_build()
+            .unwrap();
+
+        let space = pool.get_space().unwrap();
+
+        pool.reserve(2);
+        assert_eq!(pool.capacity(), 6);
+
+        drop(space);
+
+        pool.resize(1);
+        assert_eq!(pool.capacity(), 2);
+    }
+
+    #[test]
+    #[cfg(all(feature = "mmap", feature = "realtime"))]
+    fn it_should_keep_mappings_locked_when_they_grow() {
+        for backend in [StorageBackend::Anonymous, StorageBackend::Memfd] {
+            let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+                .with_buffer_size(512)
+                .with_capacity(4)
+                .with_storage_backend(backend)
+                .with_mlock(true)
+                .try_build()
+                .unwrap();
+
+            assert_eq!(crate::locked_kb(pool.get_space().unwrap().as_ptr()), 16);
+
+            pool.try_reserve(4).unwrap();
+            assert_eq!(crate::locked_kb(pool.get_space().unwrap().as_ptr()), 32);
+        }
+    }
+
+    #[test]
+    fn it_should_align_every_buffer() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(8)
+            .with_alignment(64)
+            .build();
+
+        assert_eq!(pool.stride(), 16);
+
+        let spaces: Vec<_> = (0..4).map(|_| pool.get_space().unwrap()).collect();
+
+        for space in spaces.iter() {
+            assert_eq!(space.as_ref().len(), 10);
+            assert_eq!(space.as_ref().as_ptr() as usize % 64, 0);
+        }
+
+        let span = pool.get_contiguous_space(3).unwrap();
+        assert_eq!(span.as_ref().len(), 2 * 16 + 10);
+        assert_eq!(span.as_ref().as_ptr() as usize % 64, 0);
+
+        drop(spaces);
+        drop(span);
+
+        pool.change_buffer_size(20);
+        assert_eq!(pool.stride(), 32);
+        assert_eq!(pool.capacity(), 8);
+        assert_eq!(pool.get_space().unwrap().as_ref().as_ptr() as usize % 64, 0);
+    }
+
+    #[test]
+    fn it_should_align_segmented_buffers() {
+        let mut pool: BufferPool<u8> = BufferPoolBuilder::new()
+            .with_buffer_size(3)
+            .with_capacity(2)
+            .with_storage_layout(StorageLayout::Segmented(2))
+            .with_alignment(32)
+            .build();
+
+        let _spaces: Vec<_> = (0..6)
+            .map(|_| {
+                pool.try_reserve(1).unwrap();
+
+                let space = pool.get_space().unwrap();
+                assert_eq!(space.as_ref().as_ptr() as usize % 32, 0);
+                space
+            })
+            .collect();
+    }
+
+    #[test]
+    #[should_panic(expected = "Alignment must be a power of two!")]
+    fn it_should_only_align_to_powers_of_two() {
+        let _ = BufferPoolBuilder::<f32>::new().with_alignment(48);
+    }
+
+    #[test]
+    fn it_should_work_like_a_slice() {
+        let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+            .with_buffer_size(4)
+            .with_capacity(2)
+            .build();
+
+        let mut a = pool.get_cleared_space().unwrap();
+        let mut b = pool.get_cleared_space().unwrap();
+
+        for value in &mut a {
+            *value = 1;
+        }
+
+        a[0] = 10;
+        a[1..3].copy_from_slice(&[20, 30]);
+        b.copy_from_slice(&a);
+
+        assert_eq!(a, b);
+        assert_eq!(a, [10, 20, 30, 1][..]);
+        assert_eq!(&a[2..], [30, 1]);
+        assert_eq!((&b).into_iter().sum::<usize>(), 61);
+        assert_eq!(format!("{:?}", a), "[10, 20, 30, 1]");
+
+        let slice: &[usize] = core::borrow::Borrow::borrow(&a);
+        assert_eq!(slice.len(), 4);
+
+        b[3] = 4;
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn it_should_return_split_space_when_both_halves_are_dropped() {
+        let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+            .with_buffer_size(4)
+            .with_capacity(1)
+            .build();
+
+        let (mut left, mut right) = pool.get_space().unwrap().split_at(1);
+
+        left[0] = 1;
+        right.copy_from_slice(&[2, 3, 4]);
+
+        assert_eq!(left.len(), 1);
+        assert_eq!(right.len(), 3);
+
+        let (middle, end) = right.split_at(2);
+        assert_eq!(middle, [2, 3][..]);
+        assert_eq!(end, [4][..]);
+
+        drop(left);
+        drop(middle);
+        assert!(pool.get_space().is_err());
+
+        drop(end);
+        assert_eq!(*pool.get_space().unwrap(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Split point is out of bounds!")]
+    fn it_should_panic_when_splitting_past_the_end() {
+        let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+            .with_buffer_size(4)
+            .with_capacity(1)
+            .build();
+
+        let _ = pool.get_space().unwrap().split_at(5);
+    }
+
+    #[test]
+    fn it_should_share_a_frozen_buffer_until_the_last_clone_drops() {
+        let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+            .with_buffer_size(2)
+            .with_capacity(1)
+            .build();
+
+        let mut a = pool.get_space().unwrap();
+        a.copy_from_slice(&[1, 2]);
+
+        let shared = a.freeze();
+        let clone = shared.clone();
+
+        assert_eq!(clone, [1, 2][..]);
+        assert_eq!(shared.iter().sum::<usize>(), 3);
+
+        let shared = shared.try_unfreeze().unwrap_err();
+        drop(clone);
+
+        let mut a = shared.try_unfreeze().unwrap();
+        a[0] = 3;
+        assert!(pool.get_space().is_err());
+
+        let shared = a.freeze();
+        drop(shared);
+        assert_eq!(*pool.get_space().unwrap(), [3, 2]);
+    }
+
+    #[test]
+    fn it_should_wake_the_task_acquiring_space_when_space_is_returned() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::task::Wake;
+
+        struct CountingWaker(AtomicUsize);
+
+        impl Wake for CountingWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(Arc::clone(&counter));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+            .with_buffer_size(2)
+            .with_capacity(1)
+            .build();
+
+        let a = pool.get_space().unwrap();
+        drop(a);
+
+        let a = match Pin::new(&mut pool.acquire()).poll(&mut cx) {
+            Poll::Ready(result) => result.unwrap(),
+            Poll::Pending => panic!("Space should be free"),
+        };
+
+        let mut acquire = pool.acquire();
+        assert!(Pin::new(&mut acquire).poll(&mut cx).is_pending());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+        drop(a);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        match Pin::new(&mut acquire).poll(&mut cx) {
+            Poll::Ready(result) => assert_eq!(result.unwrap().len(), 2),
+            Poll::Pending => panic!("Space should be free"),
+        }
+    }
+
+    #[test]
+    fn it_should_count_one_exhaustion_for_each_acquire_that_waits() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+            .with_buffer_size(2)
+            .with_capacity(1)
+            .build();
+
+        let a = pool.get_space().unwrap();
+        let mut acquire = pool.acquire();
+
+        for _ in 0..3 {
+            assert!(Pin::new(&mut acquire).poll(&mut cx).is_pending());
+        }
+
+        drop(a);
+        assert!(Pin::new(&mut acquire).poll(&mut cx).is_ready());
+
+        assert_eq!(pool.stats().exhaustions, 1);
+        assert_eq!(pool.stats().acquisitions, 2);
+    }
+
+    #[test]
+    fn it_should_not_wait_for_space_when_nothing_is_borrowed() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut pool: BufferPool<usize> = BufferPool::default();
+
+        assert_eq!(
+            Pin::new(&mut pool.acquire()).poll(&mut cx),
+            Poll::Ready(Err(BufferPoolError::Exhausted { capacity: 0 }))
+        );
+    }
+
+    #[test]
+    fn it_should_grow_by_the_growth_policy_when_exhausted() {
+        for (growth, capacities) in [
+            (GrowthPolicy::Fixed, vec![2, 2]),
+            (GrowthPolicy::By(3), vec![2, 2, 6, 6, 6, 6, 10, 10]),
+            (GrowthPolicy::Double, vec![2, 2, 4, 4, 8, 8, 8, 8]),
+            (GrowthPolicy::UpTo(6), vec![2, 2, 4, 4, 6, 6]),
+        ] {
+            let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+                .with_buffer_size(4)
+                .with_capacity(2)
+                .with_storage_layout(StorageLayout::Segmented(2))
+                .with_growth_policy(growth)
+                .build();
+
+            let mut spaces = Vec::new();
+            let mut grown = Vec::new();
+
+            while let Ok(mut space) = pool.get_space() {
+                space[0] = spaces.len();
+                spaces.push(space);
+                grown.push(pool.capacity());
+
+                if spaces.len() == 8 {
+                    break;
+                }
+            }
+
+            assert_eq!(grown, capacities);
+
+            for (index, space) in spaces.iter().enumerate() {
+                assert_eq!(space[0], index);
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_not_grow_past_the_max_capacity() {
+        for (capacity, buffers_per_segment, max, grown) in
+            [(4, 4, 6, 4), (2, 2, 5, 4), (3, 1, 5, 5)]
+        {
+            let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+                .with_buffer_size(4)
+                .with_capacity(capacity)
+                .with_storage_layout(StorageLayout::Segmented(buffers_per_segment))
+                .with_growth_policy(GrowthPolicy::UpTo(max))
+                .build();
+
+            let spaces: Vec<_> = (0..8).map_while(|_| pool.get_space().ok()).collect();
+
+            assert_eq!(spaces.len(), grown);
+            assert_eq!(pool.capacity(), grown);
+        }
+    }
+
+    #[test]
+    fn it_should_grow_an_empty_pool() {
+        let mut pool: BufferPool<usize> = BufferPoolBuilder::new()
+            .with_buffer_size(4)
+            .with_storage_layout(StorageLayout::Segmented(1))
+            .with_growth_policy(GrowthPolicy::Double)
+            .build();
+
+        let _a = pool.get_space().unwrap();
+        let _b = pool.get_space().unwrap();
+
+        assert_eq!(pool.capacity(), 2);
+    }
+
+    #[test]
+    fn it_should_not_build_growing_pools_that_are_not_segmented() {
+        let result: Result<BufferPool<usize>, _> = BufferPoolBuilder::new()
+            .with_growth_policy(GrowthPolicy::Double)
+            .try_build();
+
+        assert_eq!(
+            result.err(),
+            Some(BufferPoolError::Unsupported {
+                option: "growth policy"
+            })
+        );
+
+        #[cfg(feature = "mmap")]
+        {
+            let result: Result<BufferPool<usize>, _> = BufferPoolBuilder::new()
+                .with_storage_layout(StorageLayout::Segmented(2))
+                .with_storage_backend(StorageBackend::Anonymous)
+                .with_growth_policy(GrowthPolicy::Double)
+                .try_build();
+
+            assert_eq!(
+                result.err(),
+                Some(BufferPoolError::Unsupported {
+                    option: "growth policy"
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn it_should_keep_stats() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(4)
+            .build();
+
+        let a = pool.get_space().unwrap();
+        let b = pool.get_contiguous_space(3).unwrap();
+
+        assert!(pool.get_space().is_err());
+        assert!(pool.get_contiguous_space(0).is_err());
+
+        drop(a);
+        drop(b);
+
+        let c = pool.get_space().unwrap();
+
+        assert_eq!(
+            pool.stats(),
+            BufferPoolStats {
+                borrowed: 1,
+                free: 3,
+                peak_borrowed: 4,
+                acquisitions: 3,
+                exhaustions: 1,
+                reserved_bytes: 160,
+            }
+        );
+
+        drop(c);
+        assert_eq!(pool.stats().borrowed, 0);
+    }
+
+    #[test]
+    fn it_should_fill_buffers_from_the_initializer() {
+        use std::sync::atomic::AtomicUsize;
+
+        struct Handle(usize);
+
+        let next = Arc::new(AtomicUsize::new(0));
+        let mut pool: BufferPool<Handle> = BufferPoolBuilder::with_initializer({
+            let next = Arc::clone(&next);
+            move || Handle(next.fetch_add(1, Ordering::Relaxed))
+        })
+        .with_buffer_size(2)
+        .with_capacity(2)
+        .with_release_policy(ReleasePolicy::Reset)
+        .build();
+
+        let a = pool.get_space().unwrap();
+        let b = pool.get_space().unwrap();
+        assert_eq!([a[0].0, a[1].0, b[0].0, b[1].0], [0, 1, 2, 3]);
+
+        drop(a);
+        assert_eq!(next.load(Ordering::Relaxed), 6);
+
+        let a = pool.get_cleared_space().unwrap();
+        assert_eq!([a[0].0, a[1].0], [6, 7]);
+    }
+
+    #[test]
+    fn it_should_clear_buffers_to_the_fill_value() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::with_fill_value(0.5)
+            .with_buffer_size(2)
+            .with_capacity(2)
+            .build();
+
+        pool.get_space().unwrap().fill(1.);
+        assert_eq!(*pool.get_space().unwrap(), [1., 1.]);
+        assert_eq!(*pool.get_cleared_space().unwrap(), [0.5, 0.5]);
+
+        pool.get_space().unwrap().fill(1.);
+
+        pool.clear();
+        pool.reserve(1);
+
+        assert_eq!(pool.buffer.borrow()[0..6], [0.5; 6]);
+    }
+}
@@ -0,0 +1,72 @@
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use crate::{page_size, BufferPoolError};
+
+/// How the memory holding the buffers is kept resident, so that touching a
+/// buffer for the first time doesn't page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct MemoryOptions {
+    pub lock: bool,
+    pub prefault: bool,
+    pub huge_pages: bool,
+}
+
+impl MemoryOptions {
+    /// Apply the options to `bytes` bytes starting at `ptr`. The memory can't
+    /// be borrowed while this runs if it's being prefaulted.
+    pub fn apply(&self, ptr: *mut u8, bytes: usize) -> Result<(), BufferPoolError> {
+        let page_size = page_size();
+
+        if self.huge_pages {
+            // `madvise` only takes whole pages, so the advice also covers the
+            // start of the first page and the end of the last one.
+            let offset = ptr as usize % page_size;
+            let start = ptr.wrapping_sub(offset);
+            let len = (bytes + offset).div_ceil(page_size) * page_size;
+
+            if unsafe { libc::madvise(start.cast(), len, libc::MADV_HUGEPAGE) } != 0 {
+                return Err(BufferPoolError::last_os_error("madvise"));
+            }
+        }
+
+        if self.prefault {
+            let mut offset = 0;
+
+            while offset < bytes {
+                // Writing the byte back makes sure the page is backed by its
+                // own memory, rather than a shared page of zeroes.
+                unsafe {
+                    let byte = ptr.add(offset).cast::<MaybeUninit<u8>>();
+                    ptr::write_volatile(byte, ptr::read_volatile(byte));
+                }
+
+                offset += page_size - (ptr as usize + offset) % page_size;
+            }
+        }
+
+        if self.lock && unsafe { libc::mlock(ptr.cast(), bytes) } != 0 {
+            return Err(BufferPoolError::last_os_error("mlock"));
+        }
+
+        Ok(())
+    }
+
+    /// Undo the options for memory that is about to be freed.
+    pub fn release(&self, ptr: *mut u8, bytes: usize) {
+        if self.lock {
+            // Locks aren't counted, and the pages at either end of a heap
+            // allocation may be shared with other locked memory, so only the
+            // pages that lie entirely inside it are unlocked.
+            let page_size = page_size();
+            let start = (ptr as usize).next_multiple_of(page_size);
+            let end = (ptr as usize + bytes) / page_size * page_size;
+
+            if end > start {
+                unsafe {
+                    libc::munlock(ptr.wrapping_add(start - ptr as usize).cast(), end - start);
+                }
+            }
+        }
+    }
+}
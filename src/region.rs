@@ -0,0 +1,297 @@
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use std::os::unix::io::RawFd;
+
+use crate::{page_size, BufferPoolError};
+
+/// Values that can live in memory shared through a mapping.
+///
+/// # Safety
+/// A value made of all zero bytes must be valid, and the type must not own
+/// anything outside of its own bytes, such as a pointer or a handle.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($ty:ty),*) => {
+        $(unsafe impl Pod for $ty {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<V: Pod, const N: usize> Pod for [V; N] {}
+
+/// A shared mapping of zeroed values, either anonymous or backed by a memfd.
+pub(crate) struct Region<V> {
+    ptr: NonNull<V>,
+    len: usize,
+    fd: Option<RawFd>,
+}
+
+impl<V> Region<V> {
+    /// Mappings always start on a page, which is at least this many bytes.
+    pub const ALIGN: usize = 4096;
+
+    /// Map `len` values that are only shared with child processes.
+    pub fn anonymous(len: usize) -> Result<Region<V>, BufferPoolError> {
+        let mut region = Region {
+            ptr: NonNull::dangling(),
+            len: 0,
+            fd: None,
+        };
+
+        region.resize(len)?;
+        Ok(region)
+    }
+
+    /// Map `len` values from a new memfd, which can be handed to other
+    /// processes.
+    pub fn memfd(len: usize) -> Result<Region<V>, BufferPoolError> {
+        let fd = unsafe { libc::memfd_create(b"bufferpool\0".as_ptr().cast(), libc::MFD_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(BufferPoolError::last_os_error("memfd_create"));
+        }
+
+        let mut region = Region {
+            ptr: NonNull::dangling(),
+            len: 0,
+            fd: Some(fd),
+        };
+
+        region.resize(len)?;
+        Ok(region)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut V {
+        self.ptr.as_ptr()
+    }
+
+    #[cfg(test)]
+    pub fn as_slice(&self) -> &[V] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [V] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Change the number of values, keeping the values that remain. New
+    /// values are zeroed and the mapping may move. Returns whether the values
+    /// were copied to new memory, which doesn't keep the `mlock` or `madvise`
+    /// of the old mapping.
+    pub fn resize(&mut self, len: usize) -> Result<bool, BufferPoolError> {
+        let old_bytes = self.len * size_of::<V>();
+        let new_bytes = len
+            .checked_mul(size_of::<V>())
+            .ok_or(BufferPoolError::InvalidLength { len })?;
+
+        if let Some(fd) = self.fd {
+            if unsafe { libc::ftruncate(fd, new_bytes as libc::off_t) } != 0 {
+                return Err(BufferPoolError::last_os_error("ftruncate"));
+            }
+        }
+
+        let mut copied = false;
+
+        let ptr = match (old_bytes, new_bytes) {
+            (_, 0) => {
+                self.unmap();
+                NonNull::dangling()
+            }
+            (0, _) => self.map(new_bytes)?,
+            // A shared anonymous mapping can't be extended, as the memory
+            // behind it keeps its size, so the values are copied to a new one.
+            _ if self.fd.is_none() && new_bytes > old_bytes => {
+                let ptr = self.map(new_bytes)?;
+
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        self.ptr.as_ptr().cast::<u8>(),
+                        ptr.as_ptr().cast::<u8>(),
+                        old_bytes,
+                    );
+                }
+
+                self.unmap();
+                copied = true;
+                ptr
+            }
+            _ => {
+                // Shrinking a shared anonymous mapping only shrinks the view
+                // of the memory behind it, so the pages past the end are
+                // removed from that memory first.
+                if self.fd.is_none() && new_bytes < old_bytes {
+                    let page_size = page_size();
+                    let start = new_bytes.next_multiple_of(page_size);
+                    let end = old_bytes.next_multiple_of(page_size);
+
+                    if end > start {
+                        let removed = unsafe {
+                            libc::madvise(
+                                self.ptr.as_ptr().cast::<u8>().add(start).cast(),
+                                end - start,
+                                libc::MADV_REMOVE,
+                            )
+                        };
+
+                        if removed != 0 {
+                            return Err(BufferPoolError::last_os_error("madvise"));
+                        }
+                    }
+                }
+
+                let ptr = unsafe {
+                    libc::mremap(
+                        self.ptr.as_ptr().cast(),
+                        old_bytes,
+                        new_bytes,
+                        libc::MREMAP_MAYMOVE,
+                    )
+                };
+
+                if ptr == libc::MAP_FAILED {
+                    return Err(BufferPoolError::last_os_error("mremap"));
+                }
+
+                NonNull::new(ptr.cast()).ok_or_else(|| BufferPoolError::last_os_error("mremap"))?
+            }
+        };
+
+        self.ptr = ptr;
+        self.len = len;
+
+        Ok(copied)
+    }
+
+    /// Map `bytes` of new memory, from the memfd if there is one.
+    fn map(&self, bytes: usize) -> Result<NonNull<V>, BufferPoolError> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                bytes,
+                libc::PROT_READ | libc::PROT_WRITE,
+                match self.fd {
+                    Some(_) => libc::MAP_SHARED,
+                    None => libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                },
+                self.fd.unwrap_or(-1),
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(BufferPoolError::last_os_error("mmap"));
+        }
+
+        NonNull::new(ptr.cast()).ok_or_else(|| BufferPoolError::last_os_error("mmap"))
+    }
+
+    fn unmap(&mut self) {
+        let bytes = self.len * size_of::<V>();
+
+        if bytes != 0 {
+            unsafe {
+                libc::munmap(self.ptr.as_ptr().cast(), bytes);
+            }
+        }
+    }
+}
+
+impl<V> Drop for Region<V> {
+    fn drop(&mut self) {
+        self.unmap();
+
+        if let Some(fd) = self.fd {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_keep_values_when_resized() {
+        let mut region: Region<u32> = Region::anonymous(4).unwrap();
+
+        assert_eq!(region.as_slice(), [0; 4]);
+        region.as_mut_slice().copy_from_slice(&[1, 2, 3, 4]);
+
+        region.resize(100_000).unwrap();
+        assert_eq!(region.as_slice()[..5], [1, 2, 3, 4, 0]);
+        assert_eq!(region.as_slice()[99_999], 0);
+
+        region.resize(2).unwrap();
+        assert_eq!(region.as_slice(), [1, 2]);
+
+        region.resize(0).unwrap();
+        assert!(region.as_slice().is_empty());
+
+        region.resize(1).unwrap();
+        assert_eq!(region.as_slice(), [0]);
+    }
+
+    /// The bytes of memory behind the mapping of the region.
+    fn allocated_bytes<V>(region: &Region<V>) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+
+        let ptr = region.ptr.as_ptr() as usize;
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+
+        let (start, end) = maps
+            .lines()
+            .filter_map(|line| line.split_once(' ')?.0.split_once('-'))
+            .map(|(start, end)| {
+                (
+                    usize::from_str_radix(start, 16).unwrap(),
+                    usize::from_str_radix(end, 16).unwrap(),
+                )
+            })
+            .find(|(start, end)| (*start..*end).contains(&ptr))
+            .unwrap();
+
+        let path = format!("/proc/self/map_files/{:x}-{:x}", start, end);
+        std::fs::metadata(path).unwrap().blocks() * 512
+    }
+
+    #[test]
+    fn it_should_release_memory_when_shrunk() {
+        for mut region in [
+            Region::<u8>::anonymous(1 << 20).unwrap(),
+            Region::<u8>::memfd(1 << 20).unwrap(),
+        ] {
+            region.as_mut_slice().fill(1);
+            assert_eq!(allocated_bytes(&region), 1 << 20);
+
+            region.resize(4096).unwrap();
+            assert_eq!(allocated_bytes(&region), 4096);
+            assert_eq!(region.as_slice(), [1; 4096]);
+        }
+    }
+
+    #[test]
+    fn it_should_share_memfd_values_through_the_fd() {
+        let mut region: Region<u8> = Region::memfd(8).unwrap();
+        region.as_mut_slice().copy_from_slice(b"bufferpl");
+
+        let fd = region.fd().unwrap();
+        let mut values = [0_u8; 8];
+
+        let read = unsafe { libc::pread(fd, values.as_mut_ptr().cast(), 8, 0) };
+
+        assert_eq!(read, 8);
+        assert_eq!(&values, b"bufferpl");
+    }
+}
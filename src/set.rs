@@ -0,0 +1,209 @@
+use alloc::vec::Vec;
+
+use crate::{BufferPool, BufferPoolBuilder, BufferPoolError, BufferPoolReference};
+
+/// A set of `BufferPool`s with different buffer sizes, one per size class.
+///
+/// A request for `len` values is served from the smallest class whose buffers
+/// fit, falling back to larger classes when that class is exhausted.
+pub struct BufferPoolSet<V> {
+    pools: Vec<BufferPool<V>>,
+}
+
+impl<V> Default for BufferPoolSet<V> {
+    fn default() -> BufferPoolSet<V> {
+        BufferPoolSet { pools: Vec::new() }
+    }
+}
+
+impl<V> BufferPoolSet<V> {
+    pub fn new() -> BufferPoolSet<V> {
+        BufferPoolSet::default()
+    }
+
+    /// Add a size class built from the builder.
+    pub fn with_class(mut self, builder: BufferPoolBuilder<V>) -> BufferPoolSet<V> {
+        self.add_class(builder);
+        self
+    }
+
+    /// Add a size class built from the builder.
+    pub fn add_class(&mut self, builder: BufferPoolBuilder<V>) {
+        let pool = builder.build();
+        let index = self
+            .pools
+            .iter()
+            .position(|other| other.get_buffer_size() > pool.get_buffer_size())
+            .unwrap_or(self.pools.len());
+
+        self.pools.insert(index, pool);
+    }
+
+    /// The pools of each size class, from smallest to largest buffer size.
+    pub fn classes(&self) -> &[BufferPool<V>] {
+        &self.pools
+    }
+
+    /// Get a reference to a slice of `len` values setting the values back to
+    /// new values from the initializer of its size class.
+    pub fn get_cleared_space(
+        &mut self,
+        len: usize,
+    ) -> Result<BufferPoolSetReference<V>, BufferPoolError> {
+        self.get_space(len).map(|mut space| {
+            space.reference.reset();
+            space
+        })
+    }
+
+    /// Get a reference to a slice of `len` values from the smallest size class
+    /// that has a free buffer.
+    pub fn get_space(&mut self, len: usize) -> Result<BufferPoolSetReference<V>, BufferPoolError> {
+        let mut error = None;
+
+        for pool in self
+            .pools
+            .iter_mut()
+            .filter(|pool| pool.get_buffer_size() >= len)
+        {
+            match pool.get_space() {
+                Ok(mut reference) => {
+                    reference.len = len;
+
+                    return Ok(BufferPoolSetReference {
+                        class_size: pool.get_buffer_size(),
+                        reference,
+                    });
+                }
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+
+        Err(error.unwrap_or(BufferPoolError::InvalidLength { len }))
+    }
+}
+
+/// A reference to a slice of one of the pools in a `BufferPoolSet`.
+/// When dropped it will return the space to the pool it came from.
+pub struct BufferPoolSetReference<V> {
+    class_size: usize,
+    reference: BufferPoolReference<V>,
+}
+
+impl<V> BufferPoolSetReference<V> {
+    /// The buffer size of the size class the space was taken from.
+    pub fn class_size(&self) -> usize {
+        self.class_size
+    }
+}
+
+impl<V> AsMut<[V]> for BufferPoolSetReference<V> {
+    fn as_mut(&mut self) -> &mut [V] {
+        self.reference.as_mut()
+    }
+}
+
+impl<V> AsRef<[V]> for BufferPoolSetReference<V> {
+    fn as_ref(&self) -> &[V] {
+        self.reference.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set() -> BufferPoolSet<f32> {
+        BufferPoolSet::new()
+            .with_class(
+                BufferPoolBuilder::new()
+                    .with_buffer_size(1024)
+                    .with_capacity(1),
+            )
+            .with_class(
+                BufferPoolBuilder::new()
+                    .with_buffer_size(64)
+                    .with_capacity(2),
+            )
+            .with_class(
+                BufferPoolBuilder::new()
+                    .with_buffer_size(256)
+                    .with_capacity(1),
+            )
+    }
+
+    #[test]
+    fn it_should_use_the_smallest_class_that_fits() {
+        let mut set = set();
+
+        let sizes: Vec<usize> = set
+            .classes()
+            .iter()
+            .map(|pool| pool.get_buffer_size())
+            .collect();
+        assert_eq!(sizes, vec![64, 256, 1024]);
+
+        let a = set.get_space(10).unwrap();
+        let b = set.get_space(64).unwrap();
+        let c = set.get_space(65).unwrap();
+
+        assert_eq!(a.as_ref().len(), 10);
+        assert_eq!(a.class_size(), 64);
+        assert_eq!(b.class_size(), 64);
+        assert_eq!(c.class_size(), 256);
+    }
+
+    #[test]
+    fn it_should_fall_back_to_larger_classes() {
+        let mut set = set();
+
+        let a = set.get_space(1).unwrap();
+        let b = set.get_space(1).unwrap();
+        let c = set.get_space(1).unwrap();
+        let d = set.get_space(1).unwrap();
+
+        assert_eq!(a.class_size(), 64);
+        assert_eq!(b.class_size(), 64);
+        assert_eq!(c.class_size(), 256);
+        assert_eq!(d.class_size(), 1024);
+
+        assert_eq!(
+            set.get_space(1).err(),
+            Some(BufferPoolError::Exhausted { capacity: 2 })
+        );
+
+        drop(c);
+
+        assert!(!set.classes()[1].is_borrowed());
+        assert!(set.classes()[0].is_borrowed());
+        assert_eq!(set.get_space(1).unwrap().class_size(), 256);
+    }
+
+    #[test]
+    fn it_should_not_serve_lengths_larger_than_every_class() {
+        let mut set = set();
+
+        assert_eq!(
+            set.get_space(1025).err(),
+            Some(BufferPoolError::InvalidLength { len: 1025 })
+        );
+
+        let space = set.get_cleared_space(1024).unwrap();
+        assert_eq!(*space.as_ref(), vec![0_f32; 1024][..]);
+    }
+
+    #[test]
+    fn it_should_clear_space_from_the_initializer_of_the_class() {
+        let mut set = BufferPoolSet::new().with_class(
+            BufferPoolBuilder::with_fill_value(0.5_f32)
+                .with_buffer_size(4)
+                .with_capacity(1),
+        );
+
+        set.get_space(4).unwrap().as_mut().fill(1.);
+
+        assert_eq!(*set.get_cleared_space(2).unwrap().as_ref(), [0.5; 2]);
+    }
+}
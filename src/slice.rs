@@ -0,0 +1,177 @@
+use core::marker::PhantomData;
+
+use crate::bitmap::Bitmap;
+use crate::BufferPoolError;
+
+/// A `BufferPool` over memory that is owned by someone else, such as a region
+/// handed over by a driver, a memory-mapped file or an array on the stack.
+///
+/// The slice is split into `len / buffer_size` buffers and any values left
+/// over at the end are never handed out. References borrow the pool, so the
+/// slice can be taken back with `into_slice` once they have all been dropped.
+pub struct SliceBufferPool<'a, V> {
+    buffer: *mut V,
+    len: usize,
+    buffer_size: usize,
+    used: Bitmap,
+    marker: PhantomData<&'a mut [V]>,
+}
+
+// The pool holds the only access to the slice for `'a`, and each buffer in it
+// is reached only through the reference that claimed its bit in the `Bitmap`.
+// References borrow the pool, so `into_slice` can't hand the slice back while
+// any of them are alive. Threads never share a buffer, they only pass `V`s.
+unsafe impl<V: Send> Send for SliceBufferPool<'_, V> {}
+unsafe impl<V: Send> Sync for SliceBufferPool<'_, V> {}
+
+impl<'a, V> SliceBufferPool<'a, V> {
+    /// Create a pool that hands out buffers of `buffer_size` values from
+    /// `slice`.
+    pub fn from_slice(slice: &'a mut [V], buffer_size: usize) -> SliceBufferPool<'a, V> {
+        let capacity = slice.len().checked_div(buffer_size).unwrap_or(0);
+
+        SliceBufferPool {
+            buffer: slice.as_mut_ptr(),
+            len: slice.len(),
+            buffer_size,
+            used: Bitmap::new(capacity),
+            marker: PhantomData,
+        }
+    }
+
+    /// Give the slice back to the caller, including any values left over
+    /// after the last buffer.
+    pub fn into_slice(self) -> &'a mut [V] {
+        unsafe { core::slice::from_raw_parts_mut(self.buffer, self.len) }
+    }
+
+    pub fn get_buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Return the max number of buffers
+    pub fn capacity(&self) -> usize {
+        self.used.len()
+    }
+
+    /// Check whether the buffer pool has no capacity
+    pub fn is_empty(&self) -> bool {
+        self.capacity() == 0
+    }
+
+    /// Checks to see whether any of the internal slices have been borrowed.
+    pub fn is_borrowed(&self) -> bool {
+        self.used.any_used()
+    }
+
+    /// Get a reference to a slice of the `SliceBufferPool` setting the values
+    /// of the pool back to their default value.
+    pub fn get_cleared_space(&self) -> Result<SliceBufferPoolReference<'_, V>, BufferPoolError>
+    where
+        V: Default,
+    {
+        self.get_space().map(|mut space| {
+            for value in space.as_mut().iter_mut() {
+                *value = V::default();
+            }
+
+            space
+        })
+    }
+
+    /// Get a reference to a slice of the `SliceBufferPool`.
+    pub fn get_space(&self) -> Result<SliceBufferPoolReference<'_, V>, BufferPoolError> {
+        self.used
+            .find_free_index_and_use()
+            .ok_or(BufferPoolError::Exhausted {
+                capacity: self.capacity(),
+            })
+            .map(|index| {
+                let buffer = unsafe {
+                    core::slice::from_raw_parts_mut(
+                        self.buffer.add(index * self.buffer_size),
+                        self.buffer_size,
+                    )
+                };
+
+                SliceBufferPoolReference {
+                    index,
+                    used: &self.used,
+                    buffer,
+                }
+            })
+    }
+}
+
+/// A reference to a slice of the `SliceBufferPool`.
+/// When dropped it will finish the borrow and return
+/// the space.
+pub struct SliceBufferPoolReference<'a, V> {
+    index: usize,
+    used: &'a Bitmap,
+    buffer: &'a mut [V],
+}
+
+impl<V> AsMut<[V]> for SliceBufferPoolReference<'_, V> {
+    fn as_mut(&mut self) -> &mut [V] {
+        self.buffer
+    }
+}
+
+impl<V> AsRef<[V]> for SliceBufferPoolReference<'_, V> {
+    fn as_ref(&self) -> &[V] {
+        self.buffer
+    }
+}
+
+impl<V> Drop for SliceBufferPoolReference<'_, V> {
+    fn drop(&mut self) {
+        if let Err(err) = self.used.set_range_free(self.index, 1) {
+            panic!("Unable to free reference: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_hand_out_buffers_from_the_slice() {
+        let mut memory = [0_usize; 10];
+        let pool = SliceBufferPool::from_slice(&mut memory, 4);
+
+        assert_eq!(pool.capacity(), 2);
+
+        let mut a = pool.get_space().unwrap();
+        let mut b = pool.get_space().unwrap();
+
+        assert_eq!(
+            pool.get_space().err(),
+            Some(BufferPoolError::Exhausted { capacity: 2 })
+        );
+
+        a.as_mut().copy_from_slice(&[1; 4]);
+        b.as_mut().copy_from_slice(&[2; 4]);
+
+        drop(a);
+        assert!(pool.is_borrowed());
+        drop(b);
+        assert!(!pool.is_borrowed());
+
+        assert_eq!(pool.into_slice(), [1, 1, 1, 1, 2, 2, 2, 2, 0, 0]);
+        assert_eq!(memory, [1, 1, 1, 1, 2, 2, 2, 2, 0, 0]);
+    }
+
+    #[test]
+    fn it_should_clear_space_in_the_slice() {
+        let mut memory = vec![1_f32; 8];
+        let pool = SliceBufferPool::from_slice(&mut memory, 8);
+
+        assert_eq!(*pool.get_cleared_space().unwrap().as_ref(), [0_f32; 8]);
+
+        let mut memory = [0_f32; 8];
+        let empty = SliceBufferPool::from_slice(&mut memory, 0);
+        assert!(empty.is_empty());
+    }
+}
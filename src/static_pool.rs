@@ -0,0 +1,181 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::BufferPoolError;
+
+/// A `BufferPool` with a fixed number of buffers that are stored inline,
+/// so it doesn't need an allocator.
+///
+/// It holds `CAP` buffers of `BUF` values and can be created in a `static`:
+///
+/// ```
+/// use bufferpool::StaticBufferPool;
+///
+/// static POOL: StaticBufferPool<f32, 64, 4> = StaticBufferPool::new(0.);
+///
+/// let mut buffer = POOL.get_space().unwrap();
+/// buffer.as_mut()[0] = 1.;
+/// ```
+///
+/// References borrow the pool for their lifetime rather than keeping it
+/// alive.
+pub struct StaticBufferPool<V, const BUF: usize, const CAP: usize> {
+    buffers: UnsafeCell<[[V; BUF]; CAP]>,
+    used: [AtomicBool; CAP],
+}
+
+// A slot's buffer is only written through the reference that won the
+// compare-and-swap of its `AtomicBool` from free to used, and the release store
+// on drop hands it over to the next winner's acquire. No two threads ever reach
+// the same buffer at once, so they only need to be able to send `V`.
+unsafe impl<V: Send, const BUF: usize, const CAP: usize> Sync for StaticBufferPool<V, BUF, CAP> {}
+
+impl<V: Copy, const BUF: usize, const CAP: usize> StaticBufferPool<V, BUF, CAP> {
+    /// Create a pool with every value set to `value`.
+    pub const fn new(value: V) -> StaticBufferPool<V, BUF, CAP> {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const FREE: AtomicBool = AtomicBool::new(false);
+
+        StaticBufferPool {
+            buffers: UnsafeCell::new([[value; BUF]; CAP]),
+            used: [FREE; CAP],
+        }
+    }
+}
+
+impl<V, const BUF: usize, const CAP: usize> StaticBufferPool<V, BUF, CAP> {
+    pub const fn get_buffer_size(&self) -> usize {
+        BUF
+    }
+
+    /// Return the max number of buffers
+    pub const fn capacity(&self) -> usize {
+        CAP
+    }
+
+    /// Check whether the buffer pool has no capacity
+    pub const fn is_empty(&self) -> bool {
+        CAP == 0
+    }
+
+    /// Checks to see whether any of the internal slices have been borrowed.
+    pub fn is_borrowed(&self) -> bool {
+        self.used.iter().any(|used| used.load(Ordering::Acquire))
+    }
+
+    /// Get a reference to a slice of the `StaticBufferPool` setting the values
+    /// of the pool back to their default value.
+    pub fn get_cleared_space(&self) -> Result<StaticBufferPoolReference<'_, V>, BufferPoolError>
+    where
+        V: Default,
+    {
+        self.get_space().map(|mut space| {
+            for value in space.as_mut().iter_mut() {
+                *value = V::default();
+            }
+
+            space
+        })
+    }
+
+    /// Get a reference to a slice of the `StaticBufferPool`.
+    pub fn get_space(&self) -> Result<StaticBufferPoolReference<'_, V>, BufferPoolError> {
+        for (index, used) in self.used.iter().enumerate() {
+            if used
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                let buffer = unsafe { &mut *self.buffers.get().cast::<[V; BUF]>().add(index) };
+
+                return Ok(StaticBufferPoolReference { used, buffer });
+            }
+        }
+
+        Err(BufferPoolError::Exhausted { capacity: CAP })
+    }
+}
+
+/// A reference to a slice of the `StaticBufferPool`.
+/// When dropped it will finish the borrow and return
+/// the space.
+pub struct StaticBufferPoolReference<'a, V> {
+    used: &'a AtomicBool,
+    buffer: &'a mut [V],
+}
+
+impl<V> AsMut<[V]> for StaticBufferPoolReference<'_, V> {
+    fn as_mut(&mut self) -> &mut [V] {
+        self.buffer
+    }
+}
+
+impl<V> AsRef<[V]> for StaticBufferPoolReference<'_, V> {
+    fn as_ref(&self) -> &[V] {
+        self.buffer
+    }
+}
+
+impl<V> Drop for StaticBufferPoolReference<'_, V> {
+    fn drop(&mut self) {
+        self.used.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    static POOL: StaticBufferPool<usize, 16, 4> = StaticBufferPool::new(0);
+
+    #[test]
+    fn it_should_get_space_if_capacity() {
+        let pool: StaticBufferPool<f32, 10, 2> = StaticBufferPool::new(1.);
+
+        let a = pool.get_space().unwrap();
+        let b = pool.get_cleared_space().unwrap();
+
+        assert_eq!(
+            pool.get_space().err(),
+            Some(BufferPoolError::Exhausted { capacity: 2 })
+        );
+        assert_eq!(*a.as_ref(), [1.; 10]);
+        assert_eq!(*b.as_ref(), [0.; 10]);
+
+        drop(a);
+
+        assert!(pool.is_borrowed());
+        assert!(pool.get_space().is_ok());
+
+        drop(b);
+
+        assert!(!pool.is_borrowed());
+    }
+
+    #[test]
+    fn it_should_share_a_static_pool_between_threads() {
+        let handles: Vec<_> = (0..4)
+            .map(|thread_index| {
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let mut space = POOL.get_space().unwrap();
+
+                        for value in space.as_mut().iter_mut() {
+                            *value = thread_index;
+                        }
+
+                        thread::yield_now();
+
+                        assert!(space.as_ref().iter().all(|value| *value == thread_index));
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(!POOL.is_borrowed());
+    }
+}
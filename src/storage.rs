@@ -0,0 +1,503 @@
+use alloc::sync::Arc;
+#[cfg(feature = "realtime")]
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "realtime")]
+use core::mem::size_of;
+#[cfg(test)]
+use core::ops::{Index, Range};
+
+use crate::aligned::AlignedBuffer;
+
+#[cfg(feature = "realtime")]
+use crate::realtime::MemoryOptions;
+#[cfg(feature = "mmap")]
+use crate::region::Region;
+use crate::BufferPoolError;
+
+/// How a `BufferPool` lays out its buffers in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageLayout {
+    /// All of the buffers live in a single contiguous vector. Growing the
+    /// pool may reallocate the vector, so it can only grow while none of the
+    /// buffers are borrowed.
+    #[default]
+    Contiguous,
+    /// The buffers live in segments that each hold the given number of
+    /// buffers. Growing the pool adds new segments without moving the
+    /// existing ones, so it can grow while buffers are borrowed.
+    Segmented(usize),
+}
+
+/// Where a `BufferPool` gets the memory for its buffers from.
+#[cfg(feature = "mmap")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageBackend {
+    /// The buffers are allocated with the global allocator.
+    #[default]
+    Heap,
+    /// The buffers live in a shared anonymous mapping, which is inherited by
+    /// child processes.
+    Anonymous,
+    /// The buffers live in a mapping of a `memfd_create` file, which can be
+    /// passed to another process. The file is closed on `exec`.
+    Memfd,
+}
+
+/// Creates the values that fill new buffers. It can be shared between
+/// threads, so a `SyncBufferPool` can use it too.
+pub(crate) enum Initializer<V> {
+    Function(fn() -> V),
+    Closure(Arc<dyn Fn() -> V + Send + Sync>),
+}
+
+impl<V> Initializer<V> {
+    pub fn call(&self) -> V {
+        match self {
+            Initializer::Function(init) => init(),
+            Initializer::Closure(init) => init(),
+        }
+    }
+}
+
+impl<V> Clone for Initializer<V> {
+    fn clone(&self) -> Initializer<V> {
+        match self {
+            Initializer::Function(init) => Initializer::Function(*init),
+            Initializer::Closure(init) => Initializer::Closure(Arc::clone(init)),
+        }
+    }
+}
+
+pub(crate) enum Buffers<V> {
+    Contiguous(AlignedBuffer<V>),
+    Segmented {
+        segments: Vec<AlignedBuffer<V>>,
+        buffers_per_segment: usize,
+        align: usize,
+    },
+    #[cfg(feature = "mmap")]
+    Mapped(Region<V>),
+}
+
+pub(crate) struct Storage<V> {
+    buffers: Buffers<V>,
+    init: Initializer<V>,
+    #[cfg(feature = "realtime")]
+    memory: MemoryOptions,
+}
+
+impl<V> Storage<V> {
+    /// Create heap storage where every buffer starts on a multiple of `align`
+    /// bytes, as long as `stride` values are a multiple of `align` bytes. New
+    /// values are created with `init`.
+    pub fn new(
+        layout: StorageLayout,
+        align: usize,
+        capacity: usize,
+        stride: usize,
+        init: Initializer<V>,
+    ) -> Storage<V> {
+        let buffers = match layout {
+            StorageLayout::Contiguous => Buffers::Contiguous(AlignedBuffer::new(align)),
+            StorageLayout::Segmented(buffers_per_segment) => {
+                assert!(
+                    buffers_per_segment > 0,
+                    "Segments must hold at least one buffer!"
+                );
+
+                Buffers::Segmented {
+                    segments: Vec::new(),
+                    buffers_per_segment,
+                    align,
+                }
+            }
+        };
+
+        let mut storage = Storage::from_buffers(buffers, init);
+        storage
+            .resize(capacity, stride)
+            .expect("Heap storage can always be resized");
+        storage
+    }
+
+    fn from_buffers(buffers: Buffers<V>, init: Initializer<V>) -> Storage<V> {
+        Storage {
+            buffers,
+            init,
+            #[cfg(feature = "realtime")]
+            memory: MemoryOptions::default(),
+        }
+    }
+
+    /// Create storage from the backend. Mapped backends always lay their
+    /// buffers out contiguously and can't be aligned past the start of a page.
+    #[cfg(feature = "mmap")]
+    pub fn with_backend(
+        backend: StorageBackend,
+        layout: StorageLayout,
+        align: usize,
+        capacity: usize,
+        stride: usize,
+        init: Initializer<V>,
+    ) -> Result<Storage<V>, BufferPoolError> {
+        let len = capacity * stride;
+
+        if backend != StorageBackend::Heap && align > Region::<V>::ALIGN {
+            return Err(BufferPoolError::InvalidAlignment { align });
+        }
+
+        let mut region = match backend {
+            StorageBackend::Heap => return Ok(Storage::new(layout, align, capacity, stride, init)),
+            StorageBackend::Anonymous => Region::anonymous(len)?,
+            StorageBackend::Memfd => Region::memfd(len)?,
+        };
+
+        fill(region.as_mut_slice(), &init);
+
+        Ok(Storage::from_buffers(Buffers::Mapped(region), init))
+    }
+
+    /// Apply the options to all of the memory in the storage, and to any
+    /// memory it gains when it's resized.
+    #[cfg(feature = "realtime")]
+    pub fn with_memory_options(
+        mut self,
+        memory: MemoryOptions,
+    ) -> Result<Storage<V>, BufferPoolError> {
+        self.memory = memory;
+
+        for (ptr, bytes) in self.allocations() {
+            self.memory.apply(ptr, bytes)?;
+        }
+
+        Ok(self)
+    }
+
+    /// The file descriptor of a memfd backed storage.
+    #[cfg(feature = "mmap")]
+    pub fn fd(&self) -> Option<std::os::unix::io::RawFd> {
+        match &self.buffers {
+            Buffers::Mapped(region) => region.fd(),
+            _ => None,
+        }
+    }
+
+    /// The number of buffers that fit in the storage.
+    pub fn capacity(&self, stride: usize) -> usize {
+        match &self.buffers {
+            Buffers::Contiguous(buffer) => buffer.len() / stride,
+            Buffers::Segmented {
+                segments,
+                buffers_per_segment,
+                ..
+            } => segments.len() * buffers_per_segment,
+            #[cfg(feature = "mmap")]
+            Buffers::Mapped(region) => region.len() / stride,
+        }
+    }
+
+    /// The number of buffers that the capacity is always a multiple of.
+    pub fn capacity_step(&self) -> usize {
+        match &self.buffers {
+            Buffers::Segmented {
+                buffers_per_segment,
+                ..
+            } => *buffers_per_segment,
+            _ => 1,
+        }
+    }
+
+    /// Whether the storage can shrink without moving or touching the buffers
+    /// that remain in it. Mappings never move when they shrink.
+    pub fn shrinks_in_place(&self) -> bool {
+        match &self.buffers {
+            Buffers::Contiguous(_) => false,
+            Buffers::Segmented { .. } => true,
+            #[cfg(feature = "mmap")]
+            Buffers::Mapped(_) => true,
+        }
+    }
+
+    /// Whether the storage can be resized without moving or touching the
+    /// buffers that remain in it.
+    pub fn resizes_in_place(&self) -> bool {
+        match &self.buffers {
+            Buffers::Contiguous(_) => false,
+            Buffers::Segmented { .. } => true,
+            #[cfg(feature = "mmap")]
+            Buffers::Mapped(_) => false,
+        }
+    }
+
+    /// The most buffers that sit next to each other in memory.
+    pub fn max_contiguous_buffers(&self) -> usize {
+        match &self.buffers {
+            Buffers::Contiguous(_) => usize::MAX,
+            #[cfg(feature = "mmap")]
+            Buffers::Mapped(_) => usize::MAX,
+            Buffers::Segmented {
+                buffers_per_segment,
+                ..
+            } => *buffers_per_segment,
+        }
+    }
+
+    /// Get a pointer to the first value of the buffer at `index`.
+    pub fn as_mut_ptr(&mut self, index: usize, stride: usize) -> *mut V {
+        match &mut self.buffers {
+            Buffers::Contiguous(buffer) => unsafe { buffer.as_mut_ptr().add(index * stride) },
+            Buffers::Segmented {
+                segments,
+                buffers_per_segment,
+                ..
+            } => unsafe {
+                segments[index / *buffers_per_segment]
+                    .as_mut_ptr()
+                    .add((index % *buffers_per_segment) * stride)
+            },
+            #[cfg(feature = "mmap")]
+            Buffers::Mapped(region) => unsafe { region.as_mut_ptr().add(index * stride) },
+        }
+    }
+
+    /// Set all of the values back to new values from the initializer.
+    pub fn clear(&mut self) {
+        let init = &self.init;
+        let clear = |buffer: &mut [V]| fill(buffer, init);
+
+        match &mut self.buffers {
+            Buffers::Contiguous(buffer) => clear(buffer),
+            Buffers::Segmented { segments, .. } => {
+                for segment in segments.iter_mut() {
+                    clear(segment);
+                }
+            }
+            #[cfg(feature = "mmap")]
+            Buffers::Mapped(region) => clear(region.as_mut_slice()),
+        }
+    }
+
+    /// Change the number of buffers, keeping the values that remain. Segmented
+    /// storage is rounded up to a whole number of segments.
+    pub fn resize(&mut self, new_len: usize, stride: usize) -> Result<(), BufferPoolError> {
+        let init = &self.init;
+        #[cfg(feature = "realtime")]
+        let memory = &self.memory;
+
+        match &mut self.buffers {
+            Buffers::Contiguous(buffer) => resize_allocation(
+                buffer,
+                new_len * stride,
+                init,
+                #[cfg(feature = "realtime")]
+                memory,
+            ),
+            Buffers::Segmented {
+                segments,
+                buffers_per_segment,
+                align,
+            } => {
+                let segment_size = *buffers_per_segment * stride;
+                let count = new_len.div_ceil(*buffers_per_segment);
+
+                #[cfg(feature = "realtime")]
+                for segment in segments.iter_mut().skip(count) {
+                    memory.release(segment.as_mut_ptr().cast(), bytes_of(segment));
+                }
+
+                segments.truncate(count);
+
+                for segment in segments.iter_mut() {
+                    resize_allocation(
+                        segment,
+                        segment_size,
+                        init,
+                        #[cfg(feature = "realtime")]
+                        memory,
+                    )?;
+                }
+
+                while segments.len() < count {
+                    let mut segment = AlignedBuffer::new(*align);
+                    let result = resize_allocation(
+                        &mut segment,
+                        segment_size,
+                        init,
+                        #[cfg(feature = "realtime")]
+                        memory,
+                    );
+
+                    segments.push(segment);
+                    result?;
+                }
+
+                Ok(())
+            }
+            #[cfg(feature = "mmap")]
+            Buffers::Mapped(region) => {
+                let old_len = region.len();
+                #[cfg_attr(not(feature = "realtime"), allow(unused_variables))]
+                let copied = region.resize(new_len * stride)?;
+
+                if region.len() > old_len {
+                    fill(&mut region.as_mut_slice()[old_len..], init);
+                }
+
+                // The mapping keeps its options when it's remapped, but not
+                // when its values are copied to new memory, so everything
+                // apart from the memory it kept needs them.
+                #[cfg(feature = "realtime")]
+                {
+                    let kept = if copied { 0 } else { old_len.min(region.len()) };
+
+                    if region.len() > kept {
+                        let start = unsafe { region.as_mut_ptr().add(kept) };
+                        let bytes = (region.len() - kept) * size_of::<V>();
+                        memory.apply(start.cast(), bytes)?;
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// The start and length in bytes of each allocation holding buffers.
+    #[cfg(feature = "realtime")]
+    fn allocations(&mut self) -> Vec<(*mut u8, usize)> {
+        match &mut self.buffers {
+            Buffers::Contiguous(buffer) => vec![(buffer.as_mut_ptr().cast(), bytes_of(buffer))],
+            Buffers::Segmented { segments, .. } => segments
+                .iter_mut()
+                .map(|segment| (segment.as_mut_ptr().cast(), bytes_of(segment)))
+                .collect(),
+            #[cfg(feature = "mmap")]
+            Buffers::Mapped(region) => {
+                vec![(region.as_mut_ptr().cast(), region.len() * size_of::<V>())]
+            }
+        }
+        .into_iter()
+        .filter(|(_, bytes)| *bytes != 0)
+        .collect()
+    }
+}
+
+/// Set every value to a new one from `init`.
+fn fill<V>(values: &mut [V], init: &Initializer<V>) {
+    for value in values.iter_mut() {
+        *value = init.call();
+    }
+}
+
+/// Resize a heap allocation to `len` values. The memory options are released
+/// while the allocation is still ours, as it may move or be freed, and applied
+/// again to wherever it ends up.
+fn resize_allocation<V>(
+    buffer: &mut AlignedBuffer<V>,
+    len: usize,
+    init: &Initializer<V>,
+    #[cfg(feature = "realtime")] memory: &MemoryOptions,
+) -> Result<(), BufferPoolError> {
+    if buffer.len() == len {
+        return Ok(());
+    }
+
+    #[cfg(feature = "realtime")]
+    memory.release(buffer.as_mut_ptr().cast(), bytes_of(buffer));
+
+    buffer.resize_with(len, || init.call());
+
+    #[cfg(feature = "realtime")]
+    memory.apply(buffer.as_mut_ptr().cast(), bytes_of(buffer))?;
+
+    Ok(())
+}
+
+#[cfg(feature = "realtime")]
+fn bytes_of<V>(buffer: &AlignedBuffer<V>) -> usize {
+    buffer.len() * size_of::<V>()
+}
+
+#[cfg(feature = "realtime")]
+impl<V> Drop for Storage<V> {
+    fn drop(&mut self) {
+        for (ptr, bytes) in self.allocations() {
+            self.memory.release(ptr, bytes);
+        }
+    }
+}
+
+// Only tests read the storage by range, and they never cross a segment.
+#[cfg(test)]
+impl<V> Index<Range<usize>> for Storage<V> {
+    type Output = [V];
+
+    fn index(&self, range: Range<usize>) -> &[V] {
+        match &self.buffers {
+            Buffers::Contiguous(buffer) => &buffer[range],
+            Buffers::Segmented { segments, .. } => {
+                let segment_size = segments.first().map_or(0, |segment| segment.len());
+                let segment = range.start / segment_size;
+                let offset = segment * segment_size;
+
+                &segments[segment][(range.start - offset)..(range.end - offset)]
+            }
+            #[cfg(feature = "mmap")]
+            Buffers::Mapped(region) => &region.as_slice()[range],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage<V: Default>(layout: StorageLayout, capacity: usize, stride: usize) -> Storage<V> {
+        Storage::new(
+            layout,
+            1,
+            capacity,
+            stride,
+            Initializer::Function(V::default),
+        )
+    }
+
+    #[test]
+    fn it_should_round_segmented_storage_up_to_whole_segments() {
+        let mut storage: Storage<f32> = storage(StorageLayout::Segmented(4), 5, 10);
+
+        assert_eq!(storage.capacity(10), 8);
+
+        storage.resize(8, 10).unwrap();
+        assert_eq!(storage.capacity(10), 8);
+
+        storage.resize(9, 10).unwrap();
+        assert_eq!(storage.capacity(10), 12);
+    }
+
+    #[test]
+    fn it_should_not_move_segments_when_growing() {
+        let mut storage: Storage<f32> = storage(StorageLayout::Segmented(1), 1, 10);
+
+        let pointer = storage.as_mut_ptr(0, 10);
+
+        storage.resize(1000, 10).unwrap();
+
+        assert_eq!(storage.as_mut_ptr(0, 10), pointer);
+    }
+
+    #[test]
+    fn it_should_keep_values_when_changing_buffer_size() {
+        let mut storage: Storage<usize> = storage(StorageLayout::Segmented(2), 2, 2);
+
+        unsafe {
+            *storage.as_mut_ptr(1, 2) = 1;
+        }
+
+        storage.resize(2, 4).unwrap();
+
+        assert_eq!(storage.capacity(4), 2);
+        assert_eq!(storage[0..8], [0, 0, 1, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,569 @@
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+#[cfg(feature = "realtime")]
+use core::mem::size_of;
+#[cfg(feature = "std")]
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use std::sync::{Mutex, MutexGuard, PoisonError, TryLockError};
+#[cfg(feature = "std")]
+use std::thread::{self, Thread};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use crate::aligned::{stride, AlignedBuffer};
+use crate::bitmap::Bitmap;
+#[cfg(feature = "realtime")]
+use crate::realtime::MemoryOptions;
+use crate::storage::Initializer;
+use crate::{BufferPoolBuilder, BufferPoolError, ReleasePolicy};
+
+struct Inner<V> {
+    buffer: AlignedBuffer<UnsafeCell<V>>,
+    init: Initializer<V>,
+    release: ReleasePolicy,
+    #[cfg(feature = "realtime")]
+    memory: MemoryOptions,
+    buffer_size: usize,
+    stride: usize,
+    used: Bitmap,
+    #[cfg(feature = "std")]
+    waiters: Waiters,
+}
+
+/// The threads waiting for a buffer to be returned to the pool.
+///
+/// Returning a buffer never blocks. It only ever tries to take the lock, and
+/// if another thread holds it that thread wakes the waiters once it lets go.
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct Waiters {
+    count: AtomicUsize,
+    missed: AtomicBool,
+    threads: Mutex<Vec<Thread>>,
+}
+
+#[cfg(feature = "std")]
+impl Waiters {
+    /// Wake the waiting threads after a buffer has been returned.
+    fn notify(&self) {
+        // Pairs with the fence in `SyncBufferPool::acquire_until`, so either
+        // the waiter sees the returned buffer or this sees the waiter.
+        fence(Ordering::SeqCst);
+
+        if self.count.load(Ordering::Relaxed) > 0 {
+            self.missed.store(true, Ordering::SeqCst);
+            self.wake();
+        }
+    }
+
+    /// Wake the waiting threads if a buffer has been returned since they were
+    /// last woken. Every thread that lets go of the lock calls this, so a
+    /// failed `try_lock` in `notify` is never lost.
+    fn wake(&self) {
+        loop {
+            fence(Ordering::SeqCst);
+
+            if !self.missed.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let threads = match self.threads.try_lock() {
+                Ok(threads) => threads,
+                Err(TryLockError::Poisoned(err)) => err.into_inner(),
+                Err(TryLockError::WouldBlock) => return,
+            };
+
+            self.missed.store(false, Ordering::SeqCst);
+
+            for thread in threads.iter() {
+                thread.unpark();
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Thread>> {
+        self.threads.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Every buffer is only ever handed out to a single `SyncBufferPoolReference`,
+// so sharing the pool between threads only ever moves values of `V` across.
+unsafe impl<V: Send> Sync for Inner<V> {}
+
+#[cfg(feature = "realtime")]
+impl<V> Drop for Inner<V> {
+    fn drop(&mut self) {
+        let bytes = self.buffer.len() * size_of::<V>();
+        self.memory.release(self.buffer.as_mut_ptr().cast(), bytes);
+    }
+}
+
+/// A thread-safe version of `BufferPool`.
+///
+/// Buffers can be taken from a shared reference to the pool and each
+/// `SyncBufferPoolReference` can be sent to, and dropped on, any thread.
+/// Dropping a reference never blocks or allocates, even when other threads
+/// are waiting for a buffer.
+pub struct SyncBufferPool<V> {
+    inner: Arc<Inner<V>>,
+}
+
+impl<V: Default> Default for SyncBufferPool<V> {
+    fn default() -> SyncBufferPool<V> {
+        BufferPoolBuilder::default().build_sync()
+    }
+}
+
+impl<V: Default> SyncBufferPool<V> {
+    pub fn builder() -> BufferPoolBuilder<V> {
+        BufferPoolBuilder::default()
+    }
+}
+
+impl<V> SyncBufferPool<V> {
+    /// Create a pool with a buffer for every bit in `used`, filled with values
+    /// from `init`.
+    pub(crate) fn new(
+        buffer_size: usize,
+        align: usize,
+        used: Bitmap,
+        init: Initializer<V>,
+        release: ReleasePolicy,
+        #[cfg(feature = "realtime")] memory: MemoryOptions,
+    ) -> Result<SyncBufferPool<V>, BufferPoolError> {
+        let stride = stride::<V>(buffer_size, align);
+        let mut buffer = AlignedBuffer::new(align);
+        buffer.resize_with(used.len() * stride, || UnsafeCell::new(init.call()));
+
+        #[cfg(feature = "realtime")]
+        memory.apply(buffer.as_mut_ptr().cast(), buffer.len() * size_of::<V>())?;
+
+        Ok(SyncBufferPool {
+            inner: Arc::new(Inner {
+                buffer,
+                init,
+                release,
+                #[cfg(feature = "realtime")]
+                memory,
+                buffer_size,
+                stride,
+                used,
+                #[cfg(feature = "std")]
+                waiters: Waiters::default(),
+            }),
+        })
+    }
+
+    pub fn get_buffer_size(&self) -> usize {
+        self.inner.buffer_size
+    }
+
+    /// The number of values from the start of one buffer to the start of the
+    /// next. This is the buffer size plus any padding needed for alignment.
+    pub fn stride(&self) -> usize {
+        self.inner.stride
+    }
+
+    /// Return the max number of buffers
+    pub fn capacity(&self) -> usize {
+        self.inner.used.len()
+    }
+
+    /// Check whether the buffer pool has no capacity
+    pub fn is_empty(&self) -> bool {
+        self.capacity() == 0
+    }
+
+    /// Checks to see whether any of the internal slices have been borrowed.
+    pub fn is_borrowed(&self) -> bool {
+        self.inner.used.any_used()
+    }
+
+    /// Get a reference to a slice of the `SyncBufferPool` setting the values of the
+    /// pool back to new values from the initializer.
+    pub fn get_cleared_space(&self) -> Result<SyncBufferPoolReference<V>, BufferPoolError> {
+        self.get_space().map(|mut space| {
+            for value in space.as_mut().iter_mut() {
+                *value = self.inner.init.call();
+            }
+
+            space
+        })
+    }
+
+    /// Get a reference to a slice of the `SyncBufferPool`.
+    pub fn get_space(&self) -> Result<SyncBufferPoolReference<V>, BufferPoolError> {
+        self.inner
+            .used
+            .find_free_index_and_use()
+            .ok_or(BufferPoolError::Exhausted {
+                capacity: self.capacity(),
+            })
+            .map(|index| {
+                let slice = unsafe {
+                    UnsafeCell::raw_get(self.inner.buffer.as_ptr().add(index * self.inner.stride))
+                };
+
+                SyncBufferPoolReference {
+                    index,
+                    parent: Arc::clone(&self.inner),
+                    buffer_size: self.inner.buffer_size,
+                    slice,
+                }
+            })
+    }
+
+    /// Get a reference to a slice of the `SyncBufferPool`, blocking the thread
+    /// until another thread returns a buffer if they are all borrowed.
+    #[cfg(feature = "std")]
+    pub fn acquire_blocking(&self) -> Result<SyncBufferPoolReference<V>, BufferPoolError> {
+        self.acquire_until(None)
+    }
+
+    /// Get a reference to a slice of the `SyncBufferPool`, blocking the thread
+    /// for up to `timeout` until another thread returns a buffer.
+    #[cfg(feature = "std")]
+    pub fn acquire_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<SyncBufferPoolReference<V>, BufferPoolError> {
+        self.acquire_until(Instant::now().checked_add(timeout))
+    }
+
+    #[cfg(feature = "std")]
+    fn acquire_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<SyncBufferPoolReference<V>, BufferPoolError> {
+        match self.get_space() {
+            Err(BufferPoolError::Exhausted { .. }) if !self.is_empty() => {}
+            result => return result,
+        }
+
+        let waiters = &self.inner.waiters;
+
+        waiters.count.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+
+        // Once the thread is in the list, every returned buffer unparks it, so
+        // it can't miss a buffer that is returned between looking and parking.
+        waiters.lock().push(thread::current());
+        waiters.wake();
+
+        let result = loop {
+            if let Ok(space) = self.get_space() {
+                break Ok(space);
+            }
+
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => thread::park_timeout(timeout),
+                    _ => break Err(BufferPoolError::Timeout),
+                },
+            }
+        };
+
+        let id = thread::current().id();
+        waiters.lock().retain(|thread| thread.id() != id);
+        waiters.wake();
+
+        waiters.count.fetch_sub(1, Ordering::Relaxed);
+        result
+    }
+}
+
+/// A reference to a slice of the `SyncBufferPool`.
+/// When dropped it will finish the borrow and return
+/// the space, regardless of which thread it is dropped on.
+pub struct SyncBufferPoolReference<V> {
+    index: usize,
+    parent: Arc<Inner<V>>,
+    slice: *mut V,
+    buffer_size: usize,
+}
+
+unsafe impl<V: Send> Send for SyncBufferPoolReference<V> {}
+unsafe impl<V: Sync> Sync for SyncBufferPoolReference<V> {}
+
+impl<V> AsMut<[V]> for SyncBufferPoolReference<V> {
+    fn as_mut(&mut self) -> &mut [V] {
+        unsafe { alloc::slice::from_raw_parts_mut(self.slice, self.buffer_size) }
+    }
+}
+
+impl<V> AsRef<[V]> for SyncBufferPoolReference<V> {
+    fn as_ref(&self) -> &[V] {
+        unsafe { alloc::slice::from_raw_parts(self.slice, self.buffer_size) }
+    }
+}
+
+impl<V> Drop for SyncBufferPoolReference<V> {
+    fn drop(&mut self) {
+        let parent = &self.parent;
+
+        if parent.release != ReleasePolicy::Keep {
+            let values = unsafe { alloc::slice::from_raw_parts_mut(self.slice, self.buffer_size) };
+            parent.release.run(values, &parent.init);
+        }
+
+        if let Err(err) = self.parent.used.set_range_free(self.index, 1) {
+            panic!("Unable to free reference: {}", err);
+        }
+
+        #[cfg(feature = "std")]
+        self.parent.waiters.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn assert_send_and_sync<T: Send + Sync>() {}
+
+    fn small_pool() -> SyncBufferPool<f32> {
+        BufferPoolBuilder::new()
+            .with_buffer_size(10)
+            .with_capacity(2)
+            .build_sync()
+    }
+
+    #[test]
+    fn it_should_be_send_and_sync() {
+        assert_send_and_sync::<SyncBufferPool<f32>>();
+        assert_send_and_sync::<SyncBufferPoolReference<f32>>();
+    }
+
+    #[test]
+    fn it_should_get_space_if_capacity() {
+        let pool: SyncBufferPool<f32> = small_pool();
+
+        let a = pool.get_space().unwrap();
+        let b = pool.get_space().unwrap();
+
+        assert!(pool.get_space().is_err());
+        assert_eq!(a.index, 0);
+        assert_eq!(b.index, 1);
+        assert!(pool.is_borrowed());
+    }
+
+    #[test]
+    fn it_should_return_space_when_dropped_on_another_thread() {
+        let pool: SyncBufferPool<f32> = small_pool();
+
+        let a = pool.get_space().unwrap();
+        let _b = pool.get_space().unwrap();
+
+        thread::spawn(move || drop(a)).join().unwrap();
+
+        assert_eq!(pool.get_space().unwrap().index, 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_block_until_space_is_returned() {
+        let pool: Arc<SyncBufferPool<f32>> = Arc::new(small_pool());
+
+        let a = pool.get_space().unwrap();
+        let _b = pool.get_space().unwrap();
+
+        let handle = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || pool.acquire_blocking().unwrap().index)
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        drop(a);
+
+        assert_eq!(handle.join().unwrap(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_time_out_when_no_space_is_returned() {
+        let pool: SyncBufferPool<f32> = small_pool();
+
+        let _a = pool.get_space().unwrap();
+        let _b = pool.get_space().unwrap();
+
+        let start = Instant::now();
+
+        assert_eq!(
+            pool.acquire_timeout(Duration::from_millis(20)).err(),
+            Some(BufferPoolError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_not_block_on_an_empty_pool() {
+        let pool: SyncBufferPool<f32> = SyncBufferPool::default();
+
+        assert_eq!(
+            pool.acquire_blocking().err(),
+            Some(BufferPoolError::Exhausted { capacity: 0 })
+        );
+    }
+
+    #[test]
+    fn it_should_hand_out_distinct_buffers_across_threads() {
+        let buffer_size = 16;
+        let pool: Arc<SyncBufferPool<usize>> = Arc::new(
+            BufferPoolBuilder::new()
+                .with_buffer_size(buffer_size)
+                .with_capacity(8)
+                .build_sync(),
+        );
+
+        let handles: Vec<_> = (0..8)
+            .map(|thread_index| {
+                let pool = Arc::clone(&pool);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let mut space = pool.get_cleared_space().unwrap();
+
+                        for value in space.as_mut().iter_mut() {
+                            *value = thread_index;
+                        }
+
+                        thread::yield_now();
+
+                        assert!(space.as_ref().iter().all(|value| *value == thread_index));
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(!pool.is_borrowed());
+    }
+
+    #[test]
+    fn it_should_align_every_buffer() {
+        let pool: SyncBufferPool<f64> = BufferPoolBuilder::new()
+            .with_buffer_size(5)
+            .with_capacity(4)
+            .with_alignment(32)
+            .build_sync();
+
+        assert_eq!(pool.stride(), 8);
+
+        let spaces: Vec<_> = (0..4).map(|_| pool.get_space().unwrap()).collect();
+
+        for space in spaces.iter() {
+            assert_eq!(space.as_ref().len(), 5);
+            assert_eq!(space.as_ref().as_ptr() as usize % 32, 0);
+        }
+    }
+
+    #[test]
+    fn it_should_not_share_cache_lines_between_buffers() {
+        let pool: SyncBufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(3)
+            .with_capacity(4)
+            .with_cache_line_padding(true)
+            .build_sync();
+
+        let line = crate::aligned::CACHE_LINE;
+        assert_eq!(pool.stride() * core::mem::size_of::<f32>(), line);
+
+        let spaces: Vec<_> = (0..4).map(|_| pool.get_space().unwrap()).collect();
+
+        for (index, space) in spaces.iter().enumerate() {
+            let start = space.as_ref().as_ptr() as usize;
+            let end = start + 3 * core::mem::size_of::<f32>() - 1;
+
+            assert_eq!(start % line, 0);
+            assert_eq!(start / line, end / line);
+
+            for other in spaces[(index + 1)..].iter() {
+                assert_ne!(start / line, other.as_ref().as_ptr() as usize / line);
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_fill_buffers_from_the_initializer() {
+        let pool: SyncBufferPool<f32> = BufferPoolBuilder::with_fill_value(0.5)
+            .with_buffer_size(2)
+            .with_capacity(1)
+            .build_sync();
+
+        let mut space = pool.get_space().unwrap();
+        assert_eq!(*space.as_ref(), [0.5; 2]);
+
+        space.as_mut().fill(1.);
+        drop(space);
+
+        assert_eq!(*pool.get_cleared_space().unwrap().as_ref(), [0.5; 2]);
+    }
+
+    #[test]
+    fn it_should_reset_buffers_when_they_are_returned() {
+        let pool: SyncBufferPool<f32> = BufferPoolBuilder::new()
+            .with_buffer_size(2)
+            .with_capacity(1)
+            .with_release_policy(ReleasePolicy::Reset)
+            .build_sync();
+
+        pool.get_space().unwrap().as_mut().fill(1.);
+
+        assert_eq!(*pool.get_space().unwrap().as_ref(), [0.; 2]);
+    }
+
+    #[test]
+    fn it_should_not_build_pools_that_grow() {
+        let result: Result<SyncBufferPool<f32>, _> = BufferPoolBuilder::new()
+            .with_growth_policy(crate::GrowthPolicy::Double)
+            .try_build_sync();
+
+        assert_eq!(
+            result.err(),
+            Some(BufferPoolError::Unsupported {
+                option: "growth policy"
+            })
+        );
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn it_should_not_build_pools_in_a_mapping() {
+        let result: Result<SyncBufferPool<f32>, _> = BufferPoolBuilder::new()
+            .with_storage_backend(crate::StorageBackend::Anonymous)
+            .try_build_sync();
+
+        assert_eq!(
+            result.err(),
+            Some(BufferPoolError::Unsupported {
+                option: "storage backend"
+            })
+        );
+    }
+
+    #[cfg(feature = "realtime")]
+    #[test]
+    fn it_should_lock_the_buffers_in_memory() {
+        let pool: SyncBufferPool<u8> = BufferPoolBuilder::new()
+            .with_buffer_size(4096)
+            .with_capacity(4)
+            .with_alignment(4096)
+            .with_mlock(true)
+            .with_prefault(true)
+            .build_sync();
+
+        // Heap pages that are locked at different times can end up in
+        // separate mappings, so every buffer is checked on its own.
+        let spaces: Vec<_> = (0..4).map(|_| pool.get_space().unwrap()).collect();
+
+        for space in spaces.iter() {
+            assert!(crate::locked_kb(space.as_ref().as_ptr()) >= 4);
+        }
+    }
+}